@@ -0,0 +1,273 @@
+use crate::data_channel::DataChannels;
+use crate::log_error;
+use crate::video::{video_content_box, video_element};
+use signalling_protocol::{Annotation, AnnotationKind, AnnotationMessage, UserId};
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, RtcDataChannel};
+
+pub const ANNOTATION_CHANNEL_LABEL: &str = "annotation";
+
+/// Canvas ids with the video each of them covers.
+pub const ANNOTATION_SURFACES: [(&str, &str); 2] = [
+    ("local_annotations", "local_video"),
+    ("external_annotations", "external_video"),
+];
+
+/// Annotations stay fully visible for this long before fading out.
+const VISIBLE_MS: f64 = 3000.0;
+const FADE_MS: f64 = 1000.0;
+const ARROW_HEAD_LENGTH: f64 = 12.0;
+
+struct Shown {
+    annotation: Annotation,
+    shown_at: f64,
+}
+
+#[derive(Default)]
+struct State {
+    shown: Vec<Shown>,
+    /// The annotation this user is drawing right now.
+    drawing: Option<Annotation>,
+    /// Whether an animation frame is scheduled.
+    animating: bool,
+}
+
+/// Annotations over the shared screen, drawn on a canvas over each video
+/// that has something playing. Everything received is forwarded to all
+/// other peers, which only does something on the presenter's side.
+#[derive(Clone, Default)]
+pub struct Annotations {
+    channels: DataChannels,
+    state: Rc<RefCell<State>>,
+}
+
+impl Annotations {
+    /// Clearing everything is dropped unless the peer `may_clear`.
+    pub fn add_channel(&self, channel: &RtcDataChannel, peer_id: UserId, may_clear: bool) {
+        let annotations = self.clone();
+        let on_message = move |peer_id: &UserId, data: JsValue| {
+            let text = match data.as_string() {
+                Some(text) => text,
+                None => return log_error("received non text annotation message"),
+            };
+            match serde_json::from_str(&text) {
+                Ok(AnnotationMessage::ClearAll) if !may_clear => {
+                    log_error(&format!("dropping annotation clear of {:?}", peer_id));
+                }
+                Ok(message) => {
+                    annotations.forward(peer_id, &text);
+                    annotations.handle_message(message);
+                }
+                Err(e) => log_error(&format!("error parsing annotation message: {}", e)),
+            }
+        };
+        self.channels
+            .add(channel, peer_id, on_message, |_: &UserId| {});
+    }
+
+    pub fn remove_channel(&self, peer_id: &UserId) {
+        self.channels.remove(peer_id);
+    }
+
+    pub fn start(&self, kind: AnnotationKind, point: (f64, f64)) {
+        self.state.borrow_mut().drawing = Some(Annotation {
+            kind,
+            points: vec![point],
+        });
+        self.animate();
+    }
+
+    pub fn extend(&self, point: (f64, f64)) {
+        if let Some(drawing) = self.state.borrow_mut().drawing.as_mut() {
+            // Arrows only need their start and end
+            if drawing.kind == AnnotationKind::Arrow {
+                drawing.points.truncate(1);
+            }
+            drawing.points.push(point);
+        }
+    }
+
+    /// Shows the annotation being drawn to everyone.
+    pub fn finish(&self) {
+        let drawing = self.state.borrow_mut().drawing.take();
+        if let Some(annotation) = drawing {
+            self.send(&AnnotationMessage::Draw(annotation.clone()));
+            self.show(annotation);
+        }
+    }
+
+    pub fn clear_all(&self) {
+        self.state.borrow_mut().shown.clear();
+        self.send(&AnnotationMessage::ClearAll);
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.borrow_mut();
+        // A scheduled frame still clears the canvases and ends the animation
+        let animating = state.animating;
+        *state = State {
+            animating,
+            ..State::default()
+        };
+        self.channels.clear();
+    }
+
+    fn handle_message(&self, message: AnnotationMessage) {
+        match message {
+            AnnotationMessage::Draw(annotation) => self.show(annotation),
+            AnnotationMessage::ClearAll => self.state.borrow_mut().shown.clear(),
+        }
+    }
+
+    fn show(&self, annotation: Annotation) {
+        self.state.borrow_mut().shown.push(Shown {
+            annotation,
+            shown_at: js_sys::Date::now(),
+        });
+        self.animate();
+    }
+
+    fn send(&self, message: &AnnotationMessage) {
+        match serde_json::to_string(message) {
+            Ok(text) => self.send_text(None, &text),
+            Err(e) => log_error(&format!("error serializing annotation: {}", e)),
+        }
+    }
+
+    fn forward(&self, from: &UserId, text: &str) {
+        self.send_text(Some(from), text);
+    }
+
+    fn send_text(&self, except: Option<&UserId>, text: &str) {
+        for (peer_id, channel) in self.channels.all() {
+            if Some(&peer_id) == except {
+                continue;
+            }
+            if let Err(e) = channel.send_with_str(text) {
+                log_error(&format!(
+                    "error sending annotation to {:?}: {:?}",
+                    peer_id, e
+                ));
+            }
+        }
+    }
+
+    fn animate(&self) {
+        if std::mem::replace(&mut self.state.borrow_mut().animating, true) {
+            return;
+        }
+        self.request_frame();
+    }
+
+    fn request_frame(&self) {
+        let annotations = self.clone();
+        let on_frame = Closure::once_into_js(move || annotations.on_frame());
+        let result = web_sys::window()
+            .unwrap_throw()
+            .request_animation_frame(on_frame.unchecked_ref());
+        if let Err(e) = result {
+            log_error(&format!("error requesting animation frame: {:?}", e));
+            self.state.borrow_mut().animating = false;
+        }
+    }
+
+    fn on_frame(&self) {
+        let now = js_sys::Date::now();
+        let active = {
+            let mut state = self.state.borrow_mut();
+            state
+                .shown
+                .retain(|shown| now - shown.shown_at < VISIBLE_MS + FADE_MS);
+            state.animating = !state.shown.is_empty() || state.drawing.is_some();
+            state.animating
+        };
+        // Rendered once more after the last annotation faded to clear it
+        let state = self.state.borrow();
+        for (canvas_id, video_id) in ANNOTATION_SURFACES.iter() {
+            if let Err(e) = render(canvas_id, video_id, &state, now) {
+                log_error(&format!("error rendering annotations: {:?}", e));
+            }
+        }
+        drop(state);
+        if active {
+            self.request_frame();
+        }
+    }
+}
+
+fn render(canvas_id: &str, video_id: &str, state: &State, now: f64) -> Result<(), JsValue> {
+    let canvas = web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.get_element_by_id(canvas_id))
+        .ok_or_else(|| format!("no {} element", canvas_id))?
+        .dyn_into::<HtmlCanvasElement>()?;
+    let context = canvas
+        .get_context("2d")?
+        .ok_or("no 2d canvas context")?
+        .dyn_into::<CanvasRenderingContext2d>()?;
+    context.clear_rect(0.0, 0.0, canvas.width() as f64, canvas.height() as f64);
+
+    let content_box = video_element(video_id).and_then(|video| video_content_box(&video));
+    let (left, top, width, height) = match content_box {
+        Some(content_box) => content_box,
+        None => return Ok(()),
+    };
+    let to_canvas = |(x, y): (f64, f64)| (left + x * width, top + y * height);
+    for shown in state.shown.iter() {
+        let age = now - shown.shown_at;
+        let opacity = (1.0 - (age - VISIBLE_MS) / FADE_MS).clamp(0.0, 1.0);
+        draw(&context, &shown.annotation, opacity, &to_canvas);
+    }
+    if let Some(drawing) = state.drawing.as_ref() {
+        draw(&context, drawing, 1.0, &to_canvas);
+    }
+    Ok(())
+}
+
+fn draw(
+    context: &CanvasRenderingContext2d,
+    annotation: &Annotation,
+    opacity: f64,
+    to_canvas: &dyn Fn((f64, f64)) -> (f64, f64),
+) {
+    let points: Vec<(f64, f64)> = annotation.points.iter().map(|p| to_canvas(*p)).collect();
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return,
+    };
+    let (color, line_width, alpha) = match annotation.kind {
+        AnnotationKind::Stroke | AnnotationKind::Arrow => ("#f0506e", 3.0, 1.0),
+        AnnotationKind::Highlight => ("#faa05a", 14.0, 0.4),
+    };
+    context.set_global_alpha(opacity * alpha);
+    context.set_stroke_style(&JsValue::from_str(color));
+    context.set_line_width(line_width);
+    context.set_line_cap("round");
+    context.set_line_join("round");
+    context.begin_path();
+    context.move_to(first.0, first.1);
+    match annotation.kind {
+        AnnotationKind::Arrow => {
+            context.line_to(last.0, last.1);
+            let angle = (last.1 - first.1).atan2(last.0 - first.0);
+            for side in [-1.0, 1.0].iter() {
+                let head_angle = angle + side * PI / 6.0;
+                context.move_to(last.0, last.1);
+                context.line_to(
+                    last.0 - ARROW_HEAD_LENGTH * head_angle.cos(),
+                    last.1 - ARROW_HEAD_LENGTH * head_angle.sin(),
+                );
+            }
+        }
+        AnnotationKind::Stroke | AnnotationKind::Highlight => {
+            for (x, y) in points.iter().skip(1) {
+                context.line_to(*x, *y);
+            }
+        }
+    }
+    context.stroke();
+}
@@ -0,0 +1,99 @@
+use js_sys::{Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+/// Labels and maximum heights, `None` capturing at full resolution.
+pub const RESOLUTIONS: [(&str, Option<u32>); 4] = [
+    ("Full resolution", None),
+    ("1080p", Some(1080)),
+    ("720p", Some(720)),
+    ("480p", Some(480)),
+];
+pub const FRAME_RATES: [u32; 4] = [5, 15, 30, 60];
+/// Labels and values of the `cursor` constraint.
+pub const CURSORS: [(&str, &str); 3] = [
+    ("Always show the cursor", "always"),
+    ("Show the cursor while moving", "motion"),
+    ("Hide the cursor", "never"),
+];
+/// Labels and values of the `displaySurface` constraint.
+pub const SURFACES: [(&str, &str); 3] = [
+    ("Prefer the entire screen", "monitor"),
+    ("Prefer a window", "window"),
+    ("Prefer a browser tab", "browser"),
+];
+
+pub const PRESETS: [(&str, CaptureSettings); 2] = [
+    (
+        "Text-heavy, 5 fps at full resolution",
+        CaptureSettings {
+            max_height: None,
+            frame_rate: 5,
+            cursor: "always",
+            surface: "window",
+        },
+    ),
+    (
+        "Video, 30 fps at 720p",
+        CaptureSettings {
+            max_height: Some(720),
+            frame_rate: 30,
+            cursor: "motion",
+            surface: "browser",
+        },
+    ),
+];
+
+/// What the screen capture asks the browser for. The browser treats every
+/// value as a hint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureSettings {
+    pub max_height: Option<u32>,
+    pub frame_rate: u32,
+    /// One of the `CURSORS` values.
+    pub cursor: &'static str,
+    /// One of the `SURFACES` values, only preselects the browser's picker.
+    pub surface: &'static str,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            max_height: None,
+            frame_rate: 30,
+            cursor: "always",
+            surface: "monitor",
+        }
+    }
+}
+
+impl CaptureSettings {
+    /// Video constraints for `getDisplayMedia`.
+    pub fn display_constraints(&self) -> Object {
+        let constraints = self.track_constraints();
+        set(&constraints, "displaySurface", &self.surface.into());
+        constraints
+    }
+
+    /// Constraints `applyConstraints` can change on a running track. They
+    /// replace all earlier ones, so leaving out the height lifts its limit.
+    pub fn track_constraints(&self) -> Object {
+        let constraints = Object::new();
+        let frame_rate = Object::new();
+        set(&frame_rate, "ideal", &self.frame_rate.into());
+        set(&frame_rate, "max", &self.frame_rate.into());
+        set(&constraints, "frameRate", &frame_rate);
+        if let Some(max_height) = self.max_height {
+            let height = Object::new();
+            set(&height, "ideal", &max_height.into());
+            set(&height, "max", &max_height.into());
+            set(&constraints, "height", &height);
+        }
+        set(&constraints, "cursor", &self.cursor.into());
+        constraints
+    }
+}
+
+fn set(object: &Object, key: &str, value: &JsValue) {
+    // Setting a property on a plain object can not fail
+    Reflect::set(object, &key.into(), value).unwrap_throw();
+}
@@ -0,0 +1,57 @@
+use crate::data_channel::DataChannels;
+use crate::log_error;
+use signalling_protocol::UserId;
+use wasm_bindgen::prelude::*;
+use web_sys::RtcDataChannel;
+use yew::Callback;
+
+pub const CHAT_CHANNEL_LABEL: &str = "chat";
+
+/// Open chat data channels by peer. Messages to peers without one have to be
+/// relayed through the signalling server.
+#[derive(Clone)]
+pub struct ChatChannels {
+    channels: DataChannels,
+    on_message: Callback<(UserId, String)>,
+}
+
+impl ChatChannels {
+    pub fn new(on_message: Callback<(UserId, String)>) -> Self {
+        Self {
+            channels: DataChannels::default(),
+            on_message,
+        }
+    }
+
+    pub fn add_channel(&self, channel: &RtcDataChannel, peer_id: UserId) {
+        let on_message = self.on_message.clone();
+        let on_channel_message = move |peer_id: &UserId, data: JsValue| match data.as_string() {
+            Some(text) => on_message.emit((peer_id.clone(), text)),
+            None => log_error("received non text chat message"),
+        };
+        self.channels
+            .add(channel, peer_id, on_channel_message, |_: &UserId| {});
+    }
+
+    pub fn remove_channel(&self, peer_id: &UserId) {
+        self.channels.remove(peer_id);
+    }
+
+    /// Returns `false` if there is no open channel to the peer.
+    pub fn send(&self, peer_id: &UserId, text: &str) -> bool {
+        match self.channels.get(peer_id) {
+            Some(channel) => match channel.send_with_str(text) {
+                Ok(()) => true,
+                Err(e) => {
+                    log_error(&format!("error sending chat message: {:?}", e));
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    pub fn clear(&self) {
+        self.channels.clear();
+    }
+}
@@ -0,0 +1,111 @@
+use crate::log;
+use signalling_protocol::UserId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{MessageEvent, RtcDataChannel};
+
+struct OpenChannel {
+    channel: RtcDataChannel,
+    on_close: Rc<dyn Fn(&UserId)>,
+}
+
+/// Open data channels of one label by peer. A channel is added once it opened
+/// and removed once it closed or its connection was closed.
+#[derive(Clone, Default)]
+pub struct DataChannels {
+    channels: Rc<RefCell<HashMap<UserId, OpenChannel>>>,
+}
+
+impl DataChannels {
+    /// Hands the data of every message on the channel to `on_message`.
+    /// `on_close` runs once the channel was removed.
+    pub fn add<M, C>(
+        &self,
+        channel: &RtcDataChannel,
+        peer_id: UserId,
+        mut on_message: M,
+        on_close: C,
+    ) where
+        M: FnMut(&UserId, JsValue) + 'static,
+        C: Fn(&UserId) + 'static,
+    {
+        let channels = self.channels.clone();
+        let channel_clone = channel.clone();
+        let open_peer_id = peer_id.clone();
+        let on_close: Rc<dyn Fn(&UserId)> = Rc::new(on_close);
+        let on_open = Closure::wrap(Box::new(move || {
+            log(&format!(
+                "{} channel to {:?} opened",
+                channel_clone.label(),
+                open_peer_id
+            ));
+            let open_channel = OpenChannel {
+                channel: channel_clone.clone(),
+                on_close: on_close.clone(),
+            };
+            channels
+                .borrow_mut()
+                .insert(open_peer_id.clone(), open_channel);
+        }) as Box<dyn FnMut()>);
+        channel.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        on_open.forget();
+
+        // A channel of an already replaced connection must not remove the
+        // new one
+        let data_channels = self.clone();
+        let channel_clone = channel.clone();
+        let close_peer_id = peer_id.clone();
+        let on_channel_close = Closure::wrap(Box::new(move || {
+            let is_open = data_channels.get(&close_peer_id).as_ref() == Some(&channel_clone);
+            if is_open {
+                data_channels.remove(&close_peer_id);
+            }
+        }) as Box<dyn FnMut()>);
+        channel.set_onclose(Some(on_channel_close.as_ref().unchecked_ref()));
+        on_channel_close.forget();
+
+        let on_channel_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            on_message(&peer_id, event.data());
+        }) as Box<dyn FnMut(MessageEvent)>);
+        channel.set_onmessage(Some(on_channel_message.as_ref().unchecked_ref()));
+        on_channel_message.forget();
+    }
+
+    /// Closes the peer's channel and runs its `on_close`. Closing a peer
+    /// connection does not fire `close` on its channels, so this is called
+    /// for them instead.
+    pub fn remove(&self, peer_id: &UserId) {
+        let removed = self.channels.borrow_mut().remove(peer_id);
+        if let Some(open_channel) = removed {
+            open_channel.channel.close();
+            (open_channel.on_close)(peer_id);
+        }
+    }
+
+    pub fn get(&self, peer_id: &UserId) -> Option<RtcDataChannel> {
+        self.channels
+            .borrow()
+            .get(peer_id)
+            .map(|open_channel| open_channel.channel.clone())
+    }
+
+    pub fn contains(&self, peer_id: &UserId) -> bool {
+        self.channels.borrow().contains_key(peer_id)
+    }
+
+    /// All open channels with their peers.
+    pub fn all(&self) -> Vec<(UserId, RtcDataChannel)> {
+        self.channels
+            .borrow()
+            .iter()
+            .map(|(peer_id, open_channel)| (peer_id.clone(), open_channel.channel.clone()))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.channels.borrow_mut().clear();
+    }
+}
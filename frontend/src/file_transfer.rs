@@ -0,0 +1,508 @@
+use crate::data_channel::DataChannels;
+use crate::log_error;
+use js_sys::{Array, Function, Promise, Uint8Array};
+use signalling_protocol::{FileMessage, UserId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+use web_sys::{Blob, File, RtcDataChannel, RtcDataChannelType, Url};
+use yew::Callback;
+
+pub const FILE_CHANNEL_LABEL: &str = "file";
+
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Sending pauses while more than this is queued on the channel.
+const MAX_BUFFERED_AMOUNT: u32 = 1024 * 1024;
+/// Progress is reported every this many chunks.
+const CHUNKS_PER_UPDATE: usize = 64;
+/// Files are held in memory on both ends.
+pub const MAX_FILE_SIZE: u64 = 512 * 1024 * 1024;
+/// Incoming files of a peer announced and not yet complete may not add up to
+/// more than this.
+const MAX_INCOMING_BYTES_PER_PEER: u64 = MAX_FILE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+    Sending,
+    Receiving,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferState {
+    InProgress,
+    Sent,
+    /// The checksum matched, carries an object URL of the received file.
+    Verified(String),
+    Cancelled,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTransfer {
+    pub peer_id: UserId,
+    pub id: u32,
+    pub direction: TransferDirection,
+    pub name: String,
+    pub size: u64,
+    pub transferred: u64,
+    pub state: TransferState,
+}
+
+struct IncomingFile {
+    size: u64,
+    sha256: String,
+    content: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    transfers: Vec<FileTransfer>,
+    incoming: HashMap<(UserId, u32), IncomingFile>,
+    next_id: u32,
+    /// Resolve and reject functions of the transfers waiting for the queue
+    /// on a peer's channel to run low.
+    waiting: HashMap<UserId, Vec<(Function, Function)>>,
+}
+
+/// File transfers over one reliable data channel per peer. Transfer ids are
+/// chosen by the sender and only unique per direction and peer.
+#[derive(Clone)]
+pub struct FileTransfers {
+    channels: DataChannels,
+    state: Rc<RefCell<State>>,
+    on_update: Callback<()>,
+}
+
+impl FileTransfers {
+    pub fn new(on_update: Callback<()>) -> Self {
+        Self {
+            channels: DataChannels::default(),
+            state: Rc::new(RefCell::new(State::default())),
+            on_update,
+        }
+    }
+
+    pub fn add_channel(&self, channel: &RtcDataChannel, peer_id: UserId) {
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        channel.set_buffered_amount_low_threshold(MAX_BUFFERED_AMOUNT / 2);
+
+        let state = self.state.clone();
+        let low_peer_id = peer_id.clone();
+        let on_buffered_amount_low = Closure::wrap(Box::new(move || {
+            let waiting = state.borrow_mut().waiting.remove(&low_peer_id);
+            for (resolve, _) in waiting.unwrap_or_default() {
+                let _ = resolve.call0(&JsValue::NULL);
+            }
+        }) as Box<dyn FnMut()>);
+        channel.set_onbufferedamountlow(Some(on_buffered_amount_low.as_ref().unchecked_ref()));
+        on_buffered_amount_low.forget();
+
+        let file_transfers = self.clone();
+        let on_message = move |peer_id: &UserId, data: JsValue| {
+            let result = match data.as_string() {
+                Some(text) => file_transfers.handle_control(peer_id, &text),
+                None => file_transfers.handle_chunk(peer_id, &Uint8Array::new(&data).to_vec()),
+            };
+            if let Err(e) = result {
+                log_error(&format!("error handling file message: {:?}", e));
+            }
+            file_transfers.on_update.emit(());
+        };
+        let file_transfers = self.clone();
+        let on_close = move |peer_id: &UserId| file_transfers.channel_closed(peer_id);
+        self.channels.add(channel, peer_id, on_message, on_close);
+    }
+
+    /// Unfinished transfers with the peer fail.
+    pub fn remove_channel(&self, peer_id: &UserId) {
+        self.channels.remove(peer_id);
+    }
+
+    pub fn has_channel(&self, peer_id: &UserId) -> bool {
+        self.channels.contains(peer_id)
+    }
+
+    pub fn transfers(&self) -> Vec<FileTransfer> {
+        self.state.borrow().transfers.clone()
+    }
+
+    /// Sends the files to every peer, one file after another. Each file is
+    /// read and hashed once, the transfers to all peers share its content.
+    /// Failed transfers only show in their state.
+    pub async fn send_files(self, peer_ids: Vec<UserId>, files: Vec<File>) -> Result<(), JsValue> {
+        for file in files {
+            let size = file.size() as u64;
+            if size > MAX_FILE_SIZE {
+                return Err(
+                    format!("{} is larger than {} bytes", file.name(), MAX_FILE_SIZE).into(),
+                );
+            }
+            let buffer = JsFuture::from(file.array_buffer()).await?;
+            let content: Rc<[u8]> = Uint8Array::new(&buffer).to_vec().into();
+            let sha256 = sha256_hex(&content).await?;
+
+            let sends = Array::new();
+            for peer_id in peer_ids.iter() {
+                let file_transfers = self.clone();
+                let peer_id = peer_id.clone();
+                let (name, sha256, content) = (file.name(), sha256.clone(), content.clone());
+                let send = async move {
+                    let result = file_transfers
+                        .send_file(&peer_id, name, sha256, &content)
+                        .await;
+                    if let Err(e) = result {
+                        log_error(&format!("error sending file to {:?}: {:?}", peer_id, e));
+                    }
+                    Ok(JsValue::UNDEFINED)
+                };
+                sends.push(&future_to_promise(send));
+            }
+            JsFuture::from(Promise::all(&sends)).await?;
+        }
+        Ok(())
+    }
+
+    /// Resolves once the whole file is queued on the channel or the transfer
+    /// was cancelled.
+    async fn send_file(
+        &self,
+        peer_id: &UserId,
+        name: String,
+        sha256: String,
+        content: &[u8],
+    ) -> Result<(), JsValue> {
+        let channel = match self.channels.get(peer_id) {
+            Some(channel) => channel,
+            None => return Err(format!("no file channel to peer {:?}", peer_id).into()),
+        };
+        let size = content.len() as u64;
+
+        let id = {
+            let mut state = self.state.borrow_mut();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.transfers.push(FileTransfer {
+                peer_id: peer_id.clone(),
+                id,
+                direction: TransferDirection::Sending,
+                name: name.clone(),
+                size,
+                transferred: 0,
+                state: TransferState::InProgress,
+            });
+            id
+        };
+        self.on_update.emit(());
+
+        let start = FileMessage::Start {
+            id,
+            name,
+            size,
+            sha256,
+        };
+        let result = match send_control(&channel, &start) {
+            Ok(()) => self.send_content(&channel, peer_id, id, content).await,
+            Err(e) => Err(e),
+        };
+        let state = match &result {
+            Ok(()) => TransferState::Sent,
+            Err(e) => TransferState::Failed(format!("{:?}", e)),
+        };
+        self.update_transfer(peer_id, id, TransferDirection::Sending, |transfer| {
+            if transfer.state == TransferState::InProgress {
+                transfer.state = state;
+            }
+        });
+        self.on_update.emit(());
+        result
+    }
+
+    async fn send_content(
+        &self,
+        channel: &RtcDataChannel,
+        peer_id: &UserId,
+        id: u32,
+        content: &[u8],
+    ) -> Result<(), JsValue> {
+        for (index, chunk) in content.chunks(CHUNK_SIZE).enumerate() {
+            while channel.buffered_amount() > MAX_BUFFERED_AMOUNT {
+                self.wait_for_buffered_amount_low(peer_id).await?;
+            }
+            if !self.is_in_progress(peer_id, id, TransferDirection::Sending) {
+                return Ok(());
+            }
+            let mut frame = Vec::with_capacity(4 + chunk.len());
+            frame.extend_from_slice(&id.to_be_bytes());
+            frame.extend_from_slice(chunk);
+            channel.send_with_u8_array(&frame)?;
+            self.update_transfer(peer_id, id, TransferDirection::Sending, |transfer| {
+                transfer.transferred += chunk.len() as u64;
+            });
+            if index % CHUNKS_PER_UPDATE == 0 {
+                self.on_update.emit(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails once the channel closed, so that a waiting transfer ends.
+    async fn wait_for_buffered_amount_low(&self, peer_id: &UserId) -> Result<(), JsValue> {
+        let promise = Promise::new(&mut |resolve, reject| {
+            self.state
+                .borrow_mut()
+                .waiting
+                .entry(peer_id.clone())
+                .or_default()
+                .push((resolve, reject));
+        });
+        JsFuture::from(promise).await?;
+        Ok(())
+    }
+
+    pub fn cancel(&self, peer_id: &UserId, id: u32, direction: TransferDirection) {
+        if !self.is_in_progress(peer_id, id, direction) {
+            return;
+        }
+        self.update_transfer(peer_id, id, direction, |transfer| {
+            transfer.state = TransferState::Cancelled;
+        });
+        let message = match direction {
+            TransferDirection::Sending => FileMessage::CancelSend { id },
+            TransferDirection::Receiving => {
+                self.state
+                    .borrow_mut()
+                    .incoming
+                    .remove(&(peer_id.clone(), id));
+                FileMessage::CancelReceive { id }
+            }
+        };
+        let channel = self.channels.get(peer_id);
+        if let Some(channel) = channel {
+            if let Err(e) = send_control(&channel, &message) {
+                log_error(&format!("error cancelling file transfer: {:?}", e));
+            }
+        }
+        self.on_update.emit(());
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.borrow_mut();
+        for transfer in state.transfers.iter() {
+            if let TransferState::Verified(url) = &transfer.state {
+                let _ = Url::revoke_object_url(url);
+            }
+        }
+        for (_, waiting) in state.waiting.drain() {
+            reject_waiting(waiting);
+        }
+        *state = State::default();
+        self.channels.clear();
+    }
+
+    /// Unfinished transfers with the peer fail.
+    fn channel_closed(&self, peer_id: &UserId) {
+        {
+            let mut state = self.state.borrow_mut();
+            state.incoming.retain(|(user_id, _), _| user_id != peer_id);
+            reject_waiting(state.waiting.remove(peer_id).unwrap_or_default());
+            for transfer in state.transfers.iter_mut() {
+                if transfer.peer_id == *peer_id && transfer.state == TransferState::InProgress {
+                    transfer.state = TransferState::Failed("connection closed".to_string());
+                }
+            }
+        }
+        self.on_update.emit(());
+    }
+
+    fn handle_control(&self, peer_id: &UserId, text: &str) -> Result<(), JsValue> {
+        let message: FileMessage = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        match message {
+            FileMessage::Start {
+                id,
+                name,
+                size,
+                sha256,
+            } => {
+                let rejection = {
+                    let mut state = self.state.borrow_mut();
+                    let in_flight: u64 = state
+                        .incoming
+                        .iter()
+                        .filter(|((user_id, _), _)| user_id == peer_id)
+                        .map(|(_, incoming)| incoming.size)
+                        .sum();
+                    let rejection = if size > MAX_FILE_SIZE {
+                        Some("file too large")
+                    } else if in_flight + size > MAX_INCOMING_BYTES_PER_PEER {
+                        Some("too many incoming files")
+                    } else {
+                        None
+                    };
+                    state.transfers.push(FileTransfer {
+                        peer_id: peer_id.clone(),
+                        id,
+                        direction: TransferDirection::Receiving,
+                        name,
+                        size,
+                        transferred: 0,
+                        state: match rejection {
+                            Some(reason) => TransferState::Failed(reason.to_string()),
+                            None => TransferState::InProgress,
+                        },
+                    });
+                    // The buffer grows with the chunks, the announced size
+                    // comes from the peer
+                    if rejection.is_none() {
+                        let incoming = IncomingFile {
+                            size,
+                            sha256,
+                            content: Vec::new(),
+                        };
+                        state.incoming.insert((peer_id.clone(), id), incoming);
+                    }
+                    rejection
+                };
+                if rejection.is_some() {
+                    let channel = self.channels.get(peer_id);
+                    if let Some(channel) = channel {
+                        send_control(&channel, &FileMessage::CancelReceive { id })?;
+                    }
+                } else if size == 0 {
+                    self.complete_if_received(peer_id, id);
+                }
+            }
+            FileMessage::CancelSend { id } => {
+                self.state
+                    .borrow_mut()
+                    .incoming
+                    .remove(&(peer_id.clone(), id));
+                self.update_transfer(peer_id, id, TransferDirection::Receiving, |transfer| {
+                    transfer.state = TransferState::Cancelled;
+                });
+            }
+            FileMessage::CancelReceive { id } => {
+                self.update_transfer(peer_id, id, TransferDirection::Sending, |transfer| {
+                    if transfer.state == TransferState::InProgress {
+                        transfer.state = TransferState::Cancelled;
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_chunk(&self, peer_id: &UserId, frame: &[u8]) -> Result<(), JsValue> {
+        if frame.len() < 4 {
+            return Err("file chunk without transfer id".into());
+        }
+        let id = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let received = {
+            let mut state = self.state.borrow_mut();
+            // Chunks still in flight after a cancel are dropped
+            let incoming = match state.incoming.get_mut(&(peer_id.clone(), id)) {
+                Some(incoming) => incoming,
+                None => return Ok(()),
+            };
+            if (incoming.content.len() + frame.len() - 4) as u64 > incoming.size {
+                state.incoming.remove(&(peer_id.clone(), id));
+                drop(state);
+                self.update_transfer(peer_id, id, TransferDirection::Receiving, |transfer| {
+                    transfer.state = TransferState::Failed("more data than announced".to_string());
+                });
+                return Err("file chunk beyond the announced size".into());
+            }
+            incoming.content.extend_from_slice(&frame[4..]);
+            incoming.content.len() as u64
+        };
+        self.update_transfer(peer_id, id, TransferDirection::Receiving, |transfer| {
+            transfer.transferred = received;
+        });
+        self.complete_if_received(peer_id, id);
+        Ok(())
+    }
+
+    fn complete_if_received(&self, peer_id: &UserId, id: u32) {
+        let key = (peer_id.clone(), id);
+        let incoming = {
+            let mut state = self.state.borrow_mut();
+            match state.incoming.get(&key) {
+                Some(incoming) if incoming.content.len() as u64 >= incoming.size => {
+                    state.incoming.remove(&key)
+                }
+                _ => None,
+            }
+        };
+        if let Some(incoming) = incoming {
+            spawn_local(self.clone().verify(peer_id.clone(), id, incoming));
+        }
+    }
+
+    async fn verify(self, peer_id: UserId, id: u32, incoming: IncomingFile) {
+        let state = match sha256_hex(&incoming.content).await {
+            Ok(digest) if digest == incoming.sha256 => match object_url(&incoming.content) {
+                Ok(url) => TransferState::Verified(url),
+                Err(e) => TransferState::Failed(format!("{:?}", e)),
+            },
+            Ok(_) => TransferState::Failed("checksum mismatch".to_string()),
+            Err(e) => TransferState::Failed(format!("can not compute checksum: {:?}", e)),
+        };
+        self.update_transfer(&peer_id, id, TransferDirection::Receiving, |transfer| {
+            transfer.state = state;
+        });
+        self.on_update.emit(());
+    }
+
+    fn is_in_progress(&self, peer_id: &UserId, id: u32, direction: TransferDirection) -> bool {
+        self.state.borrow().transfers.iter().any(|transfer| {
+            transfer.peer_id == *peer_id
+                && transfer.id == id
+                && transfer.direction == direction
+                && transfer.state == TransferState::InProgress
+        })
+    }
+
+    fn update_transfer<F: FnOnce(&mut FileTransfer)>(
+        &self,
+        peer_id: &UserId,
+        id: u32,
+        direction: TransferDirection,
+        update: F,
+    ) {
+        let mut state = self.state.borrow_mut();
+        let transfer = state.transfers.iter_mut().find(|transfer| {
+            transfer.peer_id == *peer_id && transfer.id == id && transfer.direction == direction
+        });
+        if let Some(transfer) = transfer {
+            update(transfer);
+        }
+    }
+}
+
+fn send_control(channel: &RtcDataChannel, message: &FileMessage) -> Result<(), JsValue> {
+    let text = serde_json::to_string(message).map_err(|e| e.to_string())?;
+    channel.send_with_str(&text)
+}
+
+/// Ends transfers waiting for a channel that is gone.
+fn reject_waiting(waiting: Vec<(Function, Function)>) {
+    for (_, reject) in waiting {
+        let _ = reject.call1(&JsValue::NULL, &JsValue::from_str("connection closed"));
+    }
+}
+
+async fn sha256_hex(content: &[u8]) -> Result<String, JsValue> {
+    let subtle = web_sys::window().unwrap_throw().crypto()?.subtle();
+    let promise =
+        subtle.digest_with_str_and_buffer_source("SHA-256", &Uint8Array::from(content))?;
+    let digest = Uint8Array::new(&JsFuture::from(promise).await?).to_vec();
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn object_url(content: &[u8]) -> Result<String, JsValue> {
+    let parts = Array::of1(&Uint8Array::from(content));
+    let blob = Blob::new_with_u8_array_sequence(&parts)?;
+    Url::create_object_url_with_blob(&blob)
+}
@@ -1,119 +1,84 @@
+use crate::signalling::SignallingChannel;
 use crate::{log, log_error};
-use serde::{Deserialize, Serialize};
 use signalling_protocol::*;
-use std::convert::TryInto;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    HtmlVideoElement, MediaStream, RtcIceCandidate, RtcIceCandidateInit, RtcIceConnectionState,
-    RtcPeerConnection, RtcPeerConnectionIceEvent, WebSocket,
+    RtcIceCandidate, RtcIceCandidateInit, RtcIceConnectionState, RtcPeerConnection,
+    RtcPeerConnectionIceEvent,
 };
-
-#[derive(Debug, Serialize, Deserialize)]
-struct IceCandidate {
-    pub candidate: String,
-    pub sdp_mid: String,
-    pub sdp_m_line_index: u16,
-}
+use yew::Callback;
 
 pub fn setup_rtc_connection_ice(
     connection: &RtcPeerConnection,
     session_id: SessionId,
-    web_socket: WebSocket,
+    peer_id: UserId,
+    signalling: SignallingChannel,
+    on_state_change: Callback<RtcIceConnectionState>,
 ) {
     log("setup_rtc_connection_ice");
-    let on_ice_candidate =
-        Closure::wrap(Box::new(
-            move |event: RtcPeerConnectionIceEvent| match event.candidate() {
-                Some(candidate) => {
-                    let candidate = IceCandidate {
-                        candidate: candidate.candidate(),
-                        sdp_mid: candidate.sdp_mid().unwrap(),
-                        sdp_m_line_index: candidate.sdp_m_line_index().unwrap(),
-                    };
+    let on_ice_candidate = Closure::wrap(Box::new(move |event: RtcPeerConnectionIceEvent| {
+        // A missing candidate means gathering is complete, browsers also
+        // mark the end of a generation with an empty one
+        let candidate = event
+            .candidate()
+            .filter(|candidate| !candidate.candidate().is_empty())
+            .map(|candidate| IceCandidate {
+                candidate: candidate.candidate(),
+                sdp_mid: candidate.sdp_mid(),
+                sdp_m_line_index: candidate.sdp_m_line_index(),
+            });
 
-                    let candidate = serde_json::to_string(&candidate).unwrap();
+        let signal = Signal::ICECandidate(session_id.clone(), peer_id.clone(), candidate);
 
-                    let signal = Signal::ICECandidate(session_id.clone(), candidate);
-                    let ice_candidate: String = serde_json::to_string(&signal).unwrap();
-
-                    match web_socket.send_with_str(&ice_candidate) {
-                        Ok(_) => log("ICE candidate send"),
-                        Err(_) => log_error("error sending ICE candidate"),
-                    }
-                }
-                None => {
-                    log_error("no ICE candidate found");
-                }
-            },
-        ) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
+        match signalling.send(signal) {
+            Ok(_) => log("ICE candidate send"),
+            Err(_) => log_error("error sending ICE candidate"),
+        }
+    }) as Box<dyn FnMut(RtcPeerConnectionIceEvent)>);
     connection.set_onicecandidate(Some(on_ice_candidate.as_ref().unchecked_ref()));
     on_ice_candidate.forget();
 
+    // Lost connectivity is recovered by whoever handles the state change
     let connection_clone = connection.clone();
-    let on_ice_state_change =
-        Closure::wrap(
-            Box::new(move || match connection_clone.ice_connection_state() {
-                RtcIceConnectionState::Connected => {
-                    log("RtcIceConnectionState::Connected");
-                }
-                _ => {
-                    log_error(&format!(
-                        "RtcConnectionState {:?}",
-                        connection_clone.ice_connection_state()
-                    ));
-                }
-            }) as Box<dyn FnMut()>,
-        );
+    let on_ice_state_change = Closure::wrap(Box::new(move || {
+        let state = connection_clone.ice_connection_state();
+        match state {
+            RtcIceConnectionState::Connected => {
+                log("RtcIceConnectionState::Connected");
+            }
+            _ => {
+                log_error(&format!("RtcConnectionState {:?}", state));
+            }
+        }
+        on_state_change.emit(state);
+    }) as Box<dyn FnMut()>);
     connection.set_oniceconnectionstatechange(Some(on_ice_state_change.as_ref().unchecked_ref()));
     on_ice_state_change.forget();
 
-    let connection_clone = connection.clone();
-    let on_add_stream = Closure::wrap(Box::new(move || {
-        let remote_streams = connection_clone.get_remote_streams().to_vec();
-
-        // for now handles only 1 stream
-        let first_stream = remote_streams[0].clone();
-        let media_stream: MediaStream = first_stream.try_into().unwrap();
-
-        let window = web_sys::window().unwrap();
-        let doc = window.document().unwrap();
-        let video_element = doc
-            .get_element_by_id("external_video")
-            .expect("no external_video element");
-        let video_element = video_element.dyn_into::<HtmlVideoElement>().unwrap();
-        video_element.set_src_object(Some(&media_stream));
-    }) as Box<dyn FnMut()>);
-    connection.set_onaddstream(Some(on_add_stream.as_ref().unchecked_ref()));
-    on_add_stream.forget();
     log("ok");
 }
 
 pub async fn handle_ice_candidate(
     connection: &RtcPeerConnection,
-    candidate: &str,
+    candidate: Option<&IceCandidate>,
 ) -> Result<(), JsValue> {
     log("handle_ice_candidate");
-    let ice_candidate: IceCandidate = match serde_json::from_str(candidate) {
-        Ok(x) => x,
-        Err(_) => return Err("Could not deserialize ice candidate".into()),
+    let rtc_ice = match candidate {
+        Some(ice_candidate) => {
+            let mut rtc_ice = RtcIceCandidateInit::new(&ice_candidate.candidate);
+            rtc_ice.sdp_m_line_index(ice_candidate.sdp_m_line_index);
+            rtc_ice.sdp_mid(ice_candidate.sdp_mid.as_deref());
+            Some(RtcIceCandidate::new(&rtc_ice)?)
+        }
+        None => None,
     };
 
-    let mut rtc_ice = RtcIceCandidateInit::new(&"");
-    rtc_ice.candidate(&ice_candidate.candidate);
-    rtc_ice.sdp_m_line_index(Some(ice_candidate.sdp_m_line_index));
-    rtc_ice.sdp_mid(Some(&ice_candidate.sdp_mid));
-
-    match RtcIceCandidate::new(&rtc_ice) {
-        Ok(x) => {
-            let promise = connection
-                .clone()
-                .add_ice_candidate_with_opt_rtc_ice_candidate(Some(&x));
-            JsFuture::from(promise).await?;
-        }
-        Err(e) => return Err(e),
-    }
+    let promise = connection
+        .clone()
+        .add_ice_candidate_with_opt_rtc_ice_candidate(rtc_ice.as_ref());
+    JsFuture::from(promise).await?;
     log("ok");
     Ok(())
 }
@@ -5,6 +5,7 @@ use yew::prelude::*;
 
 use signalling_protocol::*;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::rc::Rc;
 
@@ -14,13 +15,96 @@ use sdp::*;
 mod ice;
 use ice::*;
 
+mod signalling;
+use signalling::*;
+
+mod data_channel;
+
+mod chat;
+use chat::*;
+
+mod file_transfer;
+use file_transfer::*;
+
+mod remote_control;
+use remote_control::*;
+
+mod annotations;
+use annotations::*;
+
+mod video;
+use video::*;
+
+mod capture_settings;
+use capture_settings::*;
+
+mod media;
+use media::*;
+
+mod negotiation;
+use negotiation::*;
+
+const MAX_RECONNECT_DELAY_MS: i32 = 30_000;
+/// ICE restarts of a lost peer connection before giving up on it.
+const MAX_ICE_RESTARTS: u32 = 6;
+const CAMERA_TILE_SIZE: (i32, i32) = (96, 72);
+const VIDEO_SIZE: (i32, i32) = (320, 240);
+/// Data channels the offering side opens on every peer connection.
+const DATA_CHANNEL_LABELS: [&str; 4] = [
+    CHAT_CHANNEL_LABEL,
+    FILE_CHANNEL_LABEL,
+    CONTROL_CHANNEL_LABEL,
+    ANNOTATION_CHANNEL_LABEL,
+];
+
 #[derive(Debug)]
 enum Msg {
     Initialize,
-    CreatedMedia(MediaStream),
+    /// The local stream with the tracks of it captured from the screen.
+    CreatedMedia(MediaStream, Vec<MediaStreamTrack>),
+    SwitchSource,
+    SwitchedSource(MediaStream),
+    StopSharing,
     FailedMedia(JsValue),
+    ToggleTrack(String),
+    SetCaptureSettings(CaptureSettings),
+    ToggleCamera,
+    CreatedCamera(MediaStream),
+    CameraTileDragStart(i32, i32),
+    CameraTileDrag(i32, i32),
+    CameraTileDragEnd,
     CreateSession,
     ConnectToSession,
+    JoinFromLink(SessionId),
+    CopyInviteLink,
+    LeaveSession,
+    AcceptJoin(UserId),
+    DenyJoin(UserId),
+    Kick(UserId),
+    Ban(UserId),
+    SetPresenter(UserId),
+    SetCoHost(UserId, bool),
+    SendChat,
+    ChatReceived(UserId, String),
+    FilesDropped(Vec<File>),
+    CancelTransfer(UserId, u32, TransferDirection),
+    FileTransfersUpdated,
+    RequestControl,
+    ReleaseControl,
+    GrantControl(UserId),
+    DenyControl(UserId),
+    RevokeControl,
+    SendRemoteInput(RemoteInput),
+    RemoteControlUpdated,
+    SelectAnnotationTool(Option<AnnotationKind>),
+    AnnotationStart((f64, f64)),
+    AnnotationMove((f64, f64)),
+    AnnotationEnd,
+    ClearAnnotations,
+    IceStateChanged(UserId, RtcIceConnectionState),
+    RestartIce(UserId),
+    WebSocketClosed,
+    Reconnect,
     ReceivedMessageEvent(MessageEvent),
     EventHandled(bool),
     EventError(JsValue),
@@ -29,6 +113,8 @@ enum Msg {
 enum SessionStatus {
     Connected,
     NotConnected,
+    Waiting,
+    Reconnecting,
     Error,
 }
 
@@ -37,6 +123,8 @@ impl std::fmt::Display for SessionStatus {
         match self {
             SessionStatus::Connected => write!(f, "connected"),
             SessionStatus::NotConnected => write!(f, "not connected"),
+            SessionStatus::Waiting => write!(f, "waiting for host approval"),
+            SessionStatus::Reconnecting => write!(f, "reconnecting"),
             SessionStatus::Error => write!(f, "error"),
         }
     }
@@ -50,18 +138,187 @@ pub fn log_error(msg: &str) {
     console::log_1(&msg.into());
 }
 
+/// Recovery of a peer connection that lost its ICE connectivity.
+enum IceRecovery {
+    /// Restarts so far and the timer of the next one.
+    Restarting { attempts: u32, timer: i32 },
+    /// Gave up after `MAX_ICE_RESTARTS`.
+    Lost,
+}
+
+impl IceRecovery {
+    fn cancel(&self) {
+        if let IceRecovery::Restarting { timer, .. } = self {
+            web_sys::window()
+                .unwrap_throw()
+                .clear_timeout_with_handle(*timer);
+        }
+    }
+}
+
+impl std::fmt::Display for IceRecovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IceRecovery::Restarting { attempts: 0, .. } => write!(f, "reconnecting"),
+            IceRecovery::Restarting { attempts, .. } => {
+                write!(f, "reconnecting, attempt {}", attempts)
+            }
+            IceRecovery::Lost => write!(f, "connection lost"),
+        }
+    }
+}
+
 struct ModelData {
     server_socket: String,
     local_stream: Option<MediaStream>,
-    web_socket: Option<WebSocket>,
-    rtc_connection: RtcPeerConnection,
+    /// Tracks of the local stream captured from the screen, as opposed to
+    /// the microphone.
+    display_tracks: Vec<MediaStreamTrack>,
+    camera_stream: Option<MediaStream>,
+    capture_settings: CaptureSettings,
+    /// Position of the presenter's camera tile over `external_video`.
+    camera_tile: (i32, i32),
+    /// Last pointer position while the camera tile is dragged.
+    camera_tile_drag: Option<(i32, i32)>,
+    signalling: SignallingChannel,
+    user_id: Option<UserId>,
+    resume_token: Option<ResumeToken>,
+    reconnect_attempts: u32,
+    peers: HashMap<UserId, Negotiation>,
+    /// Sides this user takes when negotiating with each participant.
+    negotiation_roles: HashMap<UserId, NegotiationRole>,
+    ice_recovery: HashMap<UserId, IceRecovery>,
+    ice_state_changed: Callback<(UserId, RtcIceConnectionState)>,
     session_id: SessionId,
+    host: Option<UserId>,
+    /// Joiners in the waiting room with their display names.
+    join_requests: Vec<(UserId, String)>,
+    /// Everyone else in the session with their display names.
+    participants: Vec<(UserId, String)>,
+    /// Roles of all participants, this user included.
+    roles: HashMap<UserId, Role>,
+    chat_channels: ChatChannels,
+    /// Chat history as author and text.
+    chat: Vec<(String, String)>,
+    file_transfers: FileTransfers,
+    remote_control: RemoteControl,
+    annotations: Annotations,
+    /// Pointer input over the videos draws annotations while set.
+    annotation_tool: Option<AnnotationKind>,
+    remote_media: RemoteMedia,
     session_status: SessionStatus,
+    error: Option<SignalError>,
+}
+
+impl ModelData {
+    fn reset_session(&mut self) {
+        for (_, negotiation) in self.peers.drain() {
+            negotiation.connection().close();
+        }
+        self.negotiation_roles.clear();
+        self.stop_ice_recovery();
+        self.session_id = SessionId::default();
+        self.host = None;
+        self.join_requests.clear();
+        self.participants.clear();
+        self.roles.clear();
+        self.chat_channels.clear();
+        self.chat.clear();
+        self.file_transfers.clear();
+        self.remote_control.clear();
+        self.annotations.clear();
+        self.annotation_tool = None;
+        self.session_status = SessionStatus::NotConnected;
+        self.remote_media.clear();
+    }
+
+    /// Closing a connection does not fire `close` on its data channels, so
+    /// they are taken out of every registry here. Their handlers update the
+    /// view, the data must not be borrowed mutably.
+    fn close_peer(&self, peer_id: &UserId, negotiation: &Negotiation) {
+        negotiation.connection().close();
+        self.chat_channels.remove_channel(peer_id);
+        self.file_transfers.remove_channel(peer_id);
+        self.remote_control.remove_channel(peer_id);
+        self.annotations.remove_channel(peer_id);
+    }
+
+    fn stop_ice_recovery(&mut self) {
+        for (_, recovery) in self.ice_recovery.drain() {
+            recovery.cancel();
+        }
+    }
+
+    fn role(&self) -> Option<Role> {
+        self.user_id
+            .as_ref()
+            .and_then(|user_id| self.roles.get(user_id))
+            .cloned()
+    }
+
+    fn is_presenting(&self) -> bool {
+        self.role() == Some(Role::Presenter)
+    }
+
+    /// The local track sent for the purpose while presenting.
+    fn local_track(&self, purpose: TrackPurpose) -> Option<MediaStreamTrack> {
+        let display_track = |kind: &str| {
+            self.display_tracks
+                .iter()
+                .find(|track| track.kind() == kind)
+                .cloned()
+        };
+        match purpose {
+            TrackPurpose::Screen => display_track("video"),
+            TrackPurpose::SharedAudio => display_track("audio"),
+            TrackPurpose::Microphone => self.local_stream.as_ref().and_then(|stream| {
+                stream
+                    .get_audio_tracks()
+                    .iter()
+                    .map(|track| track.unchecked_into::<MediaStreamTrack>())
+                    .find(|track| !self.display_tracks.contains(track))
+            }),
+            TrackPurpose::Camera => self.camera_stream.as_ref().and_then(|stream| {
+                stream
+                    .get_video_tracks()
+                    .iter()
+                    .map(|track| track.unchecked_into::<MediaStreamTrack>())
+                    .next()
+            }),
+        }
+    }
+
+    fn presenter(&self) -> Option<UserId> {
+        self.roles
+            .iter()
+            .find(|(_, role)| **role == Role::Presenter)
+            .map(|(user_id, _)| user_id.clone())
+    }
+
+    fn is_host(&self) -> bool {
+        self.host.is_some() && self.host == self.user_id
+    }
+
+    fn can_moderate(&self) -> bool {
+        self.is_host() || self.role() == Some(Role::CoHost)
+    }
+
+    fn display_name(&self, peer_id: &UserId) -> String {
+        self.participants
+            .iter()
+            .find(|(user_id, _)| user_id == peer_id)
+            .map(|(_, display_name)| display_name.clone())
+            .unwrap_or_else(|| peer_id.value().clone())
+    }
 }
 
 struct Model {
     link: ComponentLink<Self>,
     data: Rc<RefCell<ModelData>>,
+    /// Code of the invite link the page was opened with. It fills the
+    /// session field, so that a join turned away for a missing password can
+    /// be retried.
+    invite_code: Option<SessionId>,
 }
 
 impl Component for Model {
@@ -70,16 +327,50 @@ impl Component for Model {
 
     fn create(_props: Self::Properties, link: ComponentLink<Self>) -> Self {
         let model_data = Rc::new(RefCell::new(ModelData {
-            server_socket: "wss://0.0.0.0:9999".to_string(),
+            server_socket: Self::signalling_url(),
             local_stream: None,
-            web_socket: None,
-            rtc_connection: RtcPeerConnection::new().unwrap_throw(),
+            display_tracks: Vec::new(),
+            camera_stream: None,
+            capture_settings: CaptureSettings::default(),
+            camera_tile: (
+                VIDEO_SIZE.0 - CAMERA_TILE_SIZE.0 - 8,
+                VIDEO_SIZE.1 - CAMERA_TILE_SIZE.1 - 8,
+            ),
+            camera_tile_drag: None,
+            signalling: SignallingChannel::default(),
+            user_id: None,
+            resume_token: None,
+            reconnect_attempts: 0,
+            peers: HashMap::new(),
+            negotiation_roles: HashMap::new(),
+            ice_recovery: HashMap::new(),
+            ice_state_changed: link
+                .callback(|(peer_id, state)| Msg::IceStateChanged(peer_id, state)),
             session_id: SessionId::default(),
+            host: None,
+            join_requests: Vec::new(),
+            participants: Vec::new(),
+            roles: HashMap::new(),
+            chat_channels: ChatChannels::new(
+                link.callback(|(peer_id, text)| Msg::ChatReceived(peer_id, text)),
+            ),
+            chat: Vec::new(),
+            file_transfers: FileTransfers::new(link.callback(|_| Msg::FileTransfersUpdated)),
+            remote_control: RemoteControl::new(link.callback(|_| Msg::RemoteControlUpdated)),
+            annotations: Annotations::default(),
+            remote_media: RemoteMedia::default(),
+            annotation_tool: None,
             session_status: SessionStatus::NotConnected,
+            error: None,
         }));
+        let invite_code = Self::join_code_from_location();
+        if let Some(session_id) = &invite_code {
+            link.send_message(Msg::JoinFromLink(session_id.clone()));
+        }
         Self {
             link,
             data: model_data,
+            invite_code,
         }
     }
 
@@ -90,19 +381,23 @@ impl Component for Model {
                 log("Starting session");
 
                 log("Initializing video");
-                self.link.send_future(async {
-                    match Self::init_video().await {
-                        Ok(md) => Msg::CreatedMedia(md),
+                let settings = self.data.borrow().capture_settings;
+                let share_audio = Self::is_checked("share_audio");
+                let microphone = Self::is_checked("microphone");
+                self.link.send_future(async move {
+                    match Self::init_video(settings, share_audio, microphone).await {
+                        Ok((md, display_tracks)) => Msg::CreatedMedia(md, display_tracks),
                         Err(e) => Msg::FailedMedia(e),
                     }
                 });
-                
 
+                if self.data.borrow().signalling.has_web_socket() {
+                    return false;
+                }
                 log("Initializing websocket");
                 match self.open_web_socket() {
-                    Ok(socket) => {
+                    Ok(()) => {
                         log("successfully create websocket");
-                        self.data.borrow_mut().web_socket = Some(socket);
                     }
                     Err(e) => {
                         log_error(&format!("failed to create websocket with error: {:?}", e));
@@ -110,65 +405,421 @@ impl Component for Model {
                     }
                 };
             }
-            Msg::CreatedMedia(media) => {
+            Msg::CreatedMedia(media, display_tracks) => {
                 log("successfully create media device");
+                // Initializing again replaces the whole capture
+                self.stop_local_stream();
+                self.handle_display_ended(&display_tracks);
                 self.data.borrow_mut().local_stream = Some(media.clone());
-
-                self.data.borrow()
-                    .rtc_connection 
-                    .add_stream(&media);
+                self.data.borrow_mut().display_tracks = display_tracks;
+                self.update_senders_in_background();
             }
+            Msg::SwitchSource => {
+                let settings = self.data.borrow().capture_settings;
+                let share_audio = Self::is_checked("share_audio");
+                self.link.send_future(async move {
+                    match Self::init_display(settings, share_audio).await {
+                        Ok(stream) => Msg::SwitchedSource(stream),
+                        Err(e) => Msg::FailedMedia(e),
+                    }
+                });
+            }
+            Msg::SwitchedSource(display_stream) => {
+                let new_tracks: Vec<MediaStreamTrack> = display_stream
+                    .get_tracks()
+                    .iter()
+                    .map(|track| track.unchecked_into())
+                    .collect();
+                self.handle_display_ended(&new_tracks);
+                self.stop_display_tracks();
+                {
+                    let mut data = self.data.borrow_mut();
+                    match data.local_stream.as_ref() {
+                        Some(stream) => {
+                            for track in new_tracks.iter() {
+                                stream.add_track(track);
+                            }
+                        }
+                        None => {
+                            Self::show_local_video(&display_stream);
+                            data.local_stream = Some(display_stream);
+                        }
+                    }
+                    data.display_tracks = new_tracks;
+                }
+                self.update_senders_in_background();
+                return true;
+            }
+            Msg::StopSharing => {
+                self.stop_display_tracks();
+                self.update_senders_in_background();
+                return true;
+            }
+            Msg::ToggleTrack(track_id) => {
+                let track = self
+                    .data
+                    .borrow()
+                    .local_stream
+                    .as_ref()
+                    .and_then(|stream| stream.get_track_by_id(&track_id));
+                if let Some(track) = track {
+                    track.set_enabled(!track.enabled());
+                }
+                return true;
+            }
+            Msg::SetCaptureSettings(settings) => {
+                self.data.borrow_mut().capture_settings = settings;
+                let track = self
+                    .data
+                    .borrow()
+                    .local_stream
+                    .as_ref()
+                    .and_then(|stream| stream.get_video_tracks().iter().next())
+                    .map(|track| track.unchecked_into::<MediaStreamTrack>());
+                if let Some(track) = track {
+                    self.link.send_future(async move {
+                        match Self::apply_capture_settings(&track, &settings).await {
+                            Ok(()) => Msg::EventHandled(false),
+                            Err(e) => Msg::EventError(e),
+                        }
+                    });
+                }
+                return true;
+            }
+            Msg::ToggleCamera => {
+                let camera_stream = self.data.borrow_mut().camera_stream.take();
+                let camera_stream = match camera_stream {
+                    Some(camera_stream) => camera_stream,
+                    None => {
+                        self.link.send_future(async {
+                            match Self::init_camera().await {
+                                Ok(stream) => Msg::CreatedCamera(stream),
+                                Err(e) => Msg::FailedMedia(e),
+                            }
+                        });
+                        return false;
+                    }
+                };
+                for track in camera_stream.get_tracks().iter() {
+                    track.unchecked_into::<MediaStreamTrack>().stop();
+                }
+                Self::show_local_camera(None);
+                self.update_senders_in_background();
+                return true;
+            }
+            Msg::CreatedCamera(stream) => {
+                Self::show_local_camera(Some(&stream));
+                self.data.borrow_mut().camera_stream = Some(stream);
+                self.update_senders_in_background();
+                return true;
+            }
+            Msg::CameraTileDragStart(x, y) => {
+                self.data.borrow_mut().camera_tile_drag = Some((x, y));
+            }
+            Msg::CameraTileDrag(x, y) => {
+                let mut data = self.data.borrow_mut();
+                let (last_x, last_y) = match data.camera_tile_drag {
+                    Some(last) => last,
+                    None => return false,
+                };
+                let (left, top) = data.camera_tile;
+                data.camera_tile = (
+                    (left + x - last_x).clamp(0, VIDEO_SIZE.0 - CAMERA_TILE_SIZE.0),
+                    (top + y - last_y).clamp(0, VIDEO_SIZE.1 - CAMERA_TILE_SIZE.1),
+                );
+                data.camera_tile_drag = Some((x, y));
+                return true;
+            }
+            Msg::CameraTileDragEnd => self.data.borrow_mut().camera_tile_drag = None,
             Msg::FailedMedia(e) => {
                 log_error(&format!(
                     "failed to create media device with error: {:?}",
                     e
                 ));
             }
-            Msg::CreateSession => match self.data.borrow_mut().web_socket.as_ref() {
-                Some(socket) => {
-                    let signal_new_session = Signal::SessionNew;
-                    let message = match serde_json::to_string(&signal_new_session) {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            log_error(&format!(
-                                "error serializing SessionNew{:?}: {:?}",
-                                signal_new_session, e
-                            ));
-                            return false;
-                        }
-                    };
-                    let _ = socket.send_with_str(&message);
+            Msg::CreateSession => {
+                let options = SessionOptions {
+                    password: Self::get_session_password(),
+                    waiting_room: Self::is_checked("waiting_room"),
+                    display_name: Self::get_input_value("display_name").unwrap_or_default(),
+                };
+                let signal_session_new = Signal::SessionNew(options);
+                if let Err(e) = self.data.borrow().signalling.send(signal_session_new) {
+                    log_error(&format!("error sending SessionNew: {:?}", e));
                 }
-                None => {
-                    log_error("web socket not opened");
+            }
+            Msg::ConnectToSession => {
+                let session = match Self::get_session_to_connect() {
+                    Ok(session) => SessionId::new(session),
+                    Err(e) => {
+                        log_error(&format!("error getting session to connect to: {:?}", e));
+                        return false;
+                    }
+                };
+                let options = JoinOptions {
+                    password: Self::get_session_password(),
+                    display_name: Self::get_input_value("display_name").unwrap_or_default(),
+                };
+                let signal_session_join = Signal::SessionJoin(session, options);
+                if let Err(e) = self.data.borrow().signalling.send(signal_session_join) {
+                    log_error(&format!("error sending SessionJoin: {:?}", e));
                 }
-            },
-            Msg::ConnectToSession => match self.data.borrow_mut().web_socket.as_ref() {
-                Some(socket) => {
-                    let session = match Self::get_session_to_connect() {
-                        Ok(session) => SessionId::new(session),
-                        Err(e) => {
-                            log_error(&format!("error getting session to connect to: {:?}", e));
-                            return false;
+            }
+            Msg::JoinFromLink(session_id) => {
+                log(&format!("joining {:?} from the invite link", session_id));
+                if !self.data.borrow().signalling.has_web_socket() {
+                    if let Err(e) = self.open_web_socket() {
+                        log_error(&format!("failed to create websocket with error: {:?}", e));
+                        return false;
+                    }
+                }
+                let options = JoinOptions {
+                    password: Self::get_session_password(),
+                    display_name: Self::prompt_display_name(),
+                };
+                let signal_session_join = Signal::SessionJoin(session_id, options);
+                if let Err(e) = self.data.borrow().signalling.send(signal_session_join) {
+                    log_error(&format!("error sending SessionJoin: {:?}", e));
+                }
+            }
+            Msg::CopyInviteLink => {
+                let invite_link = match Self::invite_link(&self.data.borrow().session_id) {
+                    Ok(invite_link) => invite_link,
+                    Err(e) => {
+                        log_error(&format!("error building invite link: {:?}", e));
+                        return false;
+                    }
+                };
+                self.link.send_future(async move {
+                    match Self::copy_to_clipboard(invite_link).await {
+                        Ok(()) => Msg::EventHandled(false),
+                        Err(e) => Msg::EventError(e),
+                    }
+                });
+            }
+            Msg::LeaveSession => {
+                let mut data = self.data.borrow_mut();
+                let signal_session_leave = Signal::SessionLeave(data.session_id.clone());
+                if let Err(e) = data.signalling.send(signal_session_leave) {
+                    log_error(&format!("error sending SessionLeave: {:?}", e));
+                }
+                data.reset_session();
+                return true;
+            }
+            Msg::AcceptJoin(peer_id) => {
+                self.send_peer_action(&peer_id, Signal::JoinAccept(peer_id.clone()));
+                return true;
+            }
+            Msg::DenyJoin(peer_id) => {
+                self.send_peer_action(&peer_id, Signal::JoinDeny(peer_id.clone()));
+                return true;
+            }
+            Msg::Kick(peer_id) => {
+                self.send_peer_action(&peer_id, Signal::Kick(peer_id.clone()));
+                return true;
+            }
+            Msg::Ban(peer_id) => {
+                self.send_peer_action(&peer_id, Signal::Ban(peer_id.clone()));
+                return true;
+            }
+            Msg::SetPresenter(peer_id) => {
+                let signal = Signal::SetPresenter(peer_id);
+                if let Err(e) = self.data.borrow().signalling.send(signal) {
+                    log_error(&format!("error sending SetPresenter: {:?}", e));
+                }
+            }
+            Msg::SetCoHost(peer_id, co_host) => {
+                let signal = Signal::SetCoHost(peer_id, co_host);
+                if let Err(e) = self.data.borrow().signalling.send(signal) {
+                    log_error(&format!("error sending SetCoHost: {:?}", e));
+                }
+            }
+            Msg::SendChat => {
+                let text = match Self::get_input_value("chat_input") {
+                    Ok(text) if !text.trim().is_empty() => text,
+                    _ => return false,
+                };
+                Self::clear_input("chat_input");
+                let mut data = self.data.borrow_mut();
+                let session_id = data.session_id.clone();
+                for (peer_id, _) in data.participants.iter() {
+                    if data.chat_channels.send(peer_id, &text) {
+                        continue;
+                    }
+                    let signal = Signal::Chat(session_id.clone(), peer_id.clone(), text.clone());
+                    if let Err(e) = data.signalling.send(signal) {
+                        log_error(&format!("error sending Chat: {:?}", e));
+                    }
+                }
+                data.chat.push(("You".to_string(), text));
+                return true;
+            }
+            Msg::ChatReceived(peer_id, text) => {
+                let mut data = self.data.borrow_mut();
+                let author = data.display_name(&peer_id);
+                data.chat.push((author, text));
+                return true;
+            }
+            Msg::FilesDropped(files) => {
+                let file_transfers = self.data.borrow().file_transfers.clone();
+                let peer_ids: Vec<UserId> = self
+                    .data
+                    .borrow()
+                    .participants
+                    .iter()
+                    .map(|(peer_id, _)| peer_id.clone())
+                    .filter(|peer_id| file_transfers.has_channel(peer_id))
+                    .collect();
+                if peer_ids.is_empty() {
+                    log_error("no participant to send files to");
+                    return false;
+                }
+                let send = file_transfers.send_files(peer_ids, files);
+                self.link.send_future(async move {
+                    match send.await {
+                        Ok(()) => Msg::EventHandled(true),
+                        Err(e) => Msg::EventError(e),
+                    }
+                });
+            }
+            Msg::CancelTransfer(peer_id, id, direction) => {
+                self.data
+                    .borrow()
+                    .file_transfers
+                    .cancel(&peer_id, id, direction);
+            }
+            Msg::FileTransfersUpdated => return true,
+            Msg::RequestControl => {
+                let data = self.data.borrow();
+                if let Some(presenter_id) = data.presenter() {
+                    data.remote_control.request(&presenter_id);
+                }
+            }
+            Msg::ReleaseControl => {
+                let data = self.data.borrow();
+                if let Some(presenter_id) = data.presenter() {
+                    data.remote_control.release(&presenter_id);
+                }
+            }
+            Msg::GrantControl(peer_id) => self.data.borrow().remote_control.grant(&peer_id),
+            Msg::DenyControl(peer_id) => self.data.borrow().remote_control.deny(&peer_id),
+            Msg::RevokeControl => {
+                let remote_control = self.data.borrow().remote_control.clone();
+                if let Some(controller) = remote_control.controller() {
+                    remote_control.release(&controller);
+                }
+            }
+            Msg::SendRemoteInput(input) => {
+                let data = self.data.borrow();
+                if let Some(presenter_id) = data.presenter() {
+                    data.remote_control.send_input(&presenter_id, input);
+                }
+            }
+            Msg::RemoteControlUpdated => return true,
+            Msg::SelectAnnotationTool(tool) => {
+                let mut data = self.data.borrow_mut();
+                data.annotations.finish();
+                data.annotation_tool = tool;
+                return true;
+            }
+            Msg::AnnotationStart(point) => {
+                let data = self.data.borrow();
+                if let Some(kind) = data.annotation_tool {
+                    data.annotations.start(kind, point);
+                }
+            }
+            Msg::AnnotationMove(point) => self.data.borrow().annotations.extend(point),
+            Msg::AnnotationEnd => self.data.borrow().annotations.finish(),
+            Msg::ClearAnnotations => self.data.borrow().annotations.clear_all(),
+            Msg::IceStateChanged(peer_id, state) => {
+                let mut data = self.data.borrow_mut();
+                match state {
+                    RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                        if let Some(recovery) = data.ice_recovery.remove(&peer_id) {
+                            log(&format!("connection to {:?} recovered", peer_id));
+                            recovery.cancel();
                         }
-                    };
-                    let signal_session_join = Signal::SessionJoin(session);
-                    let message = match serde_json::to_string(&signal_session_join) {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            log_error(&format!(
-                                "error serializing SessionJoin{:?}: {:?}",
-                                signal_session_join, e
-                            ));
+                    }
+                    // A disconnected connection may come back on its own until
+                    // the first restart is due
+                    RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed => {
+                        if data.ice_recovery.contains_key(&peer_id) {
                             return false;
                         }
-                    };
-                    let _ = socket.send_with_str(&message);
+                        let restart = Msg::RestartIce(peer_id.clone());
+                        let recovery = match self.send_message_later(restart, backoff_delay(0)) {
+                            Ok(timer) => IceRecovery::Restarting { attempts: 0, timer },
+                            Err(e) => {
+                                log_error(&format!("error scheduling ICE restart: {:?}", e));
+                                IceRecovery::Lost
+                            }
+                        };
+                        data.ice_recovery.insert(peer_id, recovery);
+                    }
+                    _ => return false,
                 }
-                None => {
-                    log_error("web socket not opened");
+                return true;
+            }
+            Msg::RestartIce(peer_id) => {
+                let mut data = self.data.borrow_mut();
+                let attempts = match data.ice_recovery.get(&peer_id) {
+                    Some(IceRecovery::Restarting { attempts, .. }) => *attempts,
+                    _ => return false,
+                };
+                let negotiation = match data.peers.get(&peer_id) {
+                    Some(negotiation) => negotiation.clone(),
+                    None => {
+                        data.ice_recovery.remove(&peer_id);
+                        return true;
+                    }
+                };
+                if attempts >= MAX_ICE_RESTARTS {
+                    log_error(&format!("giving up on the connection to {:?}", peer_id));
+                    data.ice_recovery.insert(peer_id, IceRecovery::Lost);
+                    return true;
                 }
-            },
+                log(&format!("restarting ICE with {:?}", peer_id));
+                negotiation.restart_ice();
+                let attempts = attempts + 1;
+                let restart = Msg::RestartIce(peer_id.clone());
+                let recovery = match self.send_message_later(restart, backoff_delay(attempts)) {
+                    Ok(timer) => IceRecovery::Restarting { attempts, timer },
+                    Err(e) => {
+                        log_error(&format!("error scheduling ICE restart: {:?}", e));
+                        IceRecovery::Lost
+                    }
+                };
+                data.ice_recovery.insert(peer_id, recovery);
+                return true;
+            }
+            Msg::WebSocketClosed => {
+                log_error("ws: closed");
+                let delay = {
+                    let mut data = self.data.borrow_mut();
+                    data.signalling.set_disconnected();
+                    // This client will not get any further with the server
+                    if let Some(SignalError::IncompatibleProtocol { .. }) = data.error {
+                        return true;
+                    }
+                    if !data.session_id.value().is_empty() {
+                        data.session_status = SessionStatus::Reconnecting;
+                    }
+                    let delay = backoff_delay(data.reconnect_attempts);
+                    data.reconnect_attempts += 1;
+                    delay
+                };
+                log(&format!("ws: reconnecting in {}ms", delay));
+                if let Err(e) = self.send_message_later(Msg::Reconnect, delay) {
+                    log_error(&format!("error scheduling reconnect: {:?}", e));
+                }
+                return true;
+            }
+            Msg::Reconnect => {
+                if let Err(e) = self.open_web_socket() {
+                    log_error(&format!("failed to reopen websocket with error: {:?}", e));
+                    self.link.send_message(Msg::WebSocketClosed);
+                }
+            }
             Msg::ReceivedMessageEvent(event) => {
                 if let Ok(message) = event.data().dyn_into::<js_sys::JsString>() {
                     let data = self.data.clone();
@@ -199,58 +850,419 @@ impl Component for Model {
 
     fn view(&self) -> Html {
         let initialize = self.link.callback(|_| Msg::Initialize);
+        let toggle_camera = self.link.callback(|_| Msg::ToggleCamera);
+        let camera_label = match self.data.borrow().camera_stream {
+            Some(_) => "Camera off",
+            None => "Camera on",
+        };
         let create_session = self.link.callback(|_| Msg::CreateSession);
         let connect_to_session = self.link.callback(|_| Msg::ConnectToSession);
+        let leave_session = self.link.callback(|_| Msg::LeaveSession);
+        let error = match self.data.borrow().error.as_ref() {
+            Some(error) => html! {
+                <>
+                    <span class="uk-label uk-label-danger">{"Error: "}{ error }</span>
+                    <br/>
+                </>
+            },
+            None => html! {},
+        };
+        let join_requests = self
+            .data
+            .borrow()
+            .join_requests
+            .iter()
+            .map(|(user_id, display_name)| {
+                let accept_id = user_id.clone();
+                let accept = self.link.callback(move |_| Msg::AcceptJoin(accept_id.clone()));
+                let deny_id = user_id.clone();
+                let deny = self.link.callback(move |_| Msg::DenyJoin(deny_id.clone()));
+                html! {
+                    <div class="uk-alert-primary" uk-alert="">
+                        <span>{ display_name }{" wants to join"}</span>
+                        <button class="uk-button uk-button-primary uk-button-small" onclick={accept}>{"Accept"}</button>
+                        <button class="uk-button uk-button-danger uk-button-small" onclick={deny}>{"Deny"}</button>
+                    </div>
+                }
+            })
+            .collect::<Html>();
+        let participants = {
+            let data = self.data.borrow();
+            data.participants
+                .iter()
+                .map(|(user_id, display_name)| self.view_participant(&data, user_id, display_name))
+                .collect::<Html>()
+        };
+        let present = {
+            let data = self.data.borrow();
+            match data.user_id.clone() {
+                Some(user_id) if data.can_moderate() && !data.is_presenting() => {
+                    let present = self
+                        .link
+                        .callback(move |_| Msg::SetPresenter(user_id.clone()));
+                    html! {
+                        <button class="uk-button uk-button-default" onclick={present}>{"Present"}</button>
+                    }
+                }
+                _ => html! {},
+            }
+        };
+        let role = match self.data.borrow().role() {
+            Some(role) => format!("{:?}", role),
+            None => String::new(),
+        };
+        let chat = self
+            .data
+            .borrow()
+            .chat
+            .iter()
+            .map(|(author, text)| Self::view_chat_message(author, text))
+            .collect::<Html>();
+        let send_chat = self.link.callback(|_| Msg::SendChat);
+        let transfers = self
+            .data
+            .borrow()
+            .file_transfers
+            .transfers()
+            .iter()
+            .map(|transfer| self.view_transfer(transfer))
+            .collect::<Html>();
+        // Dropping is only allowed if dragover is cancelled
+        let drag_over = self.link.batch_callback(|event: DragEvent| {
+            event.prevent_default();
+            None
+        });
+        let drop_files = self.link.batch_callback(|event: DragEvent| {
+            event.prevent_default();
+            event
+                .data_transfer()
+                .and_then(|transfer| transfer.files())
+                .map(|files| {
+                    Msg::FilesDropped((0..files.length()).filter_map(|i| files.get(i)).collect())
+                })
+        });
+        let (session_label, invite) = if self.data.borrow().is_host() {
+            let copy_invite_link = self.link.callback(|_| Msg::CopyInviteLink);
+            let invite = html! {
+                <button class="uk-button uk-button-default uk-button-small" onclick={copy_invite_link}>{"Copy invite link"}</button>
+            };
+            ("Hosting Session ID: ", invite)
+        } else {
+            ("Joined Session ID: ", html! {})
+        };
+        let invite_code = self
+            .invite_code
+            .as_ref()
+            .map(|code| code.value().clone())
+            .unwrap_or_default();
         html! {
             <div class="uk-position-center uk-background-default">
                 <h1 class="uk-heading-medium">{"Web Video Chat in Rust"}</h1>
-                <span class="uk-label">{"Hosting Session ID: "}</span>
+                <span class="uk-label">{ session_label }</span>
                 <span class="uk-text-default">{ &self.data.borrow().session_id.value() }</span>
+                { invite }
                 <br/>
                 <span class="uk-label">{" Status: "}{ &self.data.borrow().session_status }</span>
+                <span class="uk-label">{" Role: "}{ role }</span>
                 <br/>
+                { error }
+                { join_requests }
+                <ul class="uk-list">{ participants }</ul>
                 <span class="uk-label">{"Current server web socket: "}{ &self.data.borrow().server_socket }</span>
                 <h1 class="uk-heading-small">{"Peer A Video"}</h1>
-                <video id="external_video" width="320" height="240" style="color: black; outline-style: solid;" autoplay=true></video>
+                { self.view_external_video() }
                 <br/>
                 <button class="uk-button uk-button-default" onclick={connect_to_session}>{"Connect to Session"}</button>
-                <input id="session_to_connect" type="text" class="uk-input"/>
+                <input id="session_to_connect" type="text" class="uk-input" value={invite_code}/>
+                <input id="session_password" type="password" class="uk-input" placeholder="Session password (optional)"/>
+                <input id="display_name" type="text" class="uk-input" placeholder="Your name"/>
                 <hr/>
                 <h1 class="uk-heading-small">{"Peer B Video"}</h1>
-                <video id="local_video" width="320" height="240" style="color: black; outline-style: solid;" autoplay=true></video>
+                { self.view_local_video() }
                 <br/>
+                <label><input id="share_audio" type="checkbox" class="uk-checkbox"/>{" Share tab or system audio"}</label>
+                <label><input id="microphone" type="checkbox" class="uk-checkbox"/>{" Add microphone"}</label>
+                { self.view_capture_settings() }
                 <button class="uk-button uk-button-default" onclick={initialize}>{"Initialize"}</button>
+                <button class="uk-button uk-button-default" onclick={toggle_camera}>{ camera_label }</button>
+                { self.view_sharing_controls() }
+                { self.view_local_tracks() }
                 <hr/>
+                <label><input id="waiting_room" type="checkbox" class="uk-checkbox"/>{" Waiting room"}</label>
                 <button class="uk-button uk-button-default" onclick={create_session}>{"Create session"}</button>
+                <button class="uk-button uk-button-default" onclick={leave_session}>{"Leave session"}</button>
+                { present }
                 <br/>
+                { self.view_remote_control() }
+                { self.view_annotation_tools() }
+                <hr/>
+                <h1 class="uk-heading-small">{"Chat"}</h1>
+                <ul class="uk-list uk-list-divider">{ chat }</ul>
+                <input id="chat_input" type="text" class="uk-input"/>
+                <button class="uk-button uk-button-default" onclick={send_chat}>{"Send"}</button>
+                <hr/>
+                <h1 class="uk-heading-small">{"Files"}</h1>
+                <div class="uk-placeholder uk-text-center" ondragover={drag_over} ondrop={drop_files}>
+                    {"Drop files here to send them to everyone in the session"}
+                </div>
+                <ul class="uk-list uk-list-divider">{ transfers }</ul>
             </div>
         }
     }
 }
 
 impl Model {
-    async fn init_video() -> Result<MediaStream, JsValue> {
-        let window = web_sys::window().ok_or("no window found")?;
-        let navigator = window.navigator();
-        let media_device = navigator.media_devices()?;
-        let stream_promise = media_device.get_display_media()?; 
-        let doc = window.document().ok_or("no doc found")?;
-        let video_element = doc
-            .get_element_by_id("local_video")
-            .expect("no local_video element");
-        let video_element = video_element.dyn_into::<HtmlVideoElement>()?;
+    /// The signalling server is served from the same origin as the frontend.
+    fn signalling_url() -> String {
+        let location = web_sys::window().unwrap_throw().location();
+        let scheme = match location.protocol() {
+            Ok(protocol) if protocol == "http:" => "ws",
+            _ => "wss",
+        };
+        let host = location.host().unwrap_throw();
+        format!("{}://{}/ws", scheme, host)
+    }
 
-        let media_stream = match wasm_bindgen_futures::JsFuture::from(stream_promise).await {
-            Ok(ms) => MediaStream::from(ms),
-            Err(e) => {
-                return Err(format!("error in getting media stream: {:?}", e).into());
+    /// Captures the screen, with the tab or system audio if `share_audio`
+    /// and a microphone track added if `microphone`.
+    async fn init_video(
+        settings: CaptureSettings,
+        share_audio: bool,
+        microphone: bool,
+    ) -> Result<(MediaStream, Vec<MediaStreamTrack>), JsValue> {
+        let media_device = web_sys::window()
+            .ok_or("no window found")?
+            .navigator()
+            .media_devices()?;
+        let media_stream = Self::init_display(settings, share_audio).await?;
+        let display_tracks = media_stream
+            .get_tracks()
+            .iter()
+            .map(|track| track.unchecked_into())
+            .collect();
+        if microphone {
+            // Sharing goes on without the microphone if it is not available
+            match Self::init_microphone(&media_device).await {
+                Ok(microphone_stream) => {
+                    for track in microphone_stream.get_audio_tracks().iter() {
+                        media_stream.add_track(&track.unchecked_into());
+                    }
+                }
+                Err(e) => log_error(&format!("error in getting microphone: {:?}", e)),
             }
+        }
+        Self::show_local_video(&media_stream);
+        Ok((media_stream, display_tracks))
+    }
+
+    async fn init_display(
+        settings: CaptureSettings,
+        share_audio: bool,
+    ) -> Result<MediaStream, JsValue> {
+        let media_device = web_sys::window()
+            .ok_or("no window found")?
+            .navigator()
+            .media_devices()?;
+        let mut constraints = DisplayMediaStreamConstraints::new();
+        constraints
+            .video(&settings.display_constraints())
+            .audio(&share_audio.into());
+        let stream_promise = media_device.get_display_media_with_constraints(&constraints)?;
+        match wasm_bindgen_futures::JsFuture::from(stream_promise).await {
+            Ok(ms) => Ok(MediaStream::from(ms)),
+            Err(e) => Err(format!("error in getting media stream: {:?}", e).into()),
+        }
+    }
+
+    fn show_local_video(stream: &MediaStream) {
+        let video_element = video_element("local_video").expect("no local_video element");
+        // The captured audio must not be played back locally
+        video_element.set_muted(true);
+        video_element.set_src_object(Some(stream));
+    }
+
+    /// Changes a running capture, the preferred surface only applies to the
+    /// next one.
+    async fn apply_capture_settings(
+        track: &MediaStreamTrack,
+        settings: &CaptureSettings,
+    ) -> Result<(), JsValue> {
+        let constraints = settings.track_constraints().unchecked_into();
+        let promise = track.apply_constraints_with_constraints(&constraints)?;
+        wasm_bindgen_futures::JsFuture::from(promise).await?;
+        Ok(())
+    }
+
+    fn view_capture_settings(&self) -> Html {
+        let settings = self.data.borrow().capture_settings;
+        let presets = PRESETS
+            .iter()
+            .map(|(label, preset)| {
+                let preset = *preset;
+                let class = if preset == settings {
+                    "uk-button uk-button-primary uk-button-small"
+                } else {
+                    "uk-button uk-button-default uk-button-small"
+                };
+                let select = self.link.callback(move |_| Msg::SetCaptureSettings(preset));
+                html! { <button class={class} onclick={select}>{ label }</button> }
+            })
+            .collect::<Html>();
+        let resolutions = RESOLUTIONS
+            .iter()
+            .enumerate()
+            .map(|(index, (label, max_height))| {
+                let selected = *max_height == settings.max_height;
+                html! { <option value={index.to_string()} selected={selected}>{ label }</option> }
+            })
+            .collect::<Html>();
+        let set_resolution = self.link.batch_callback(move |change: ChangeData| {
+            let (_, max_height) = RESOLUTIONS.get(Self::selected_index(change)?)?;
+            let max_height = *max_height;
+            Some(Msg::SetCaptureSettings(CaptureSettings {
+                max_height,
+                ..settings
+            }))
+        });
+        let frame_rates = FRAME_RATES
+            .iter()
+            .enumerate()
+            .map(|(index, frame_rate)| {
+                let selected = *frame_rate == settings.frame_rate;
+                html! { <option value={index.to_string()} selected={selected}>{ frame_rate }{" fps"}</option> }
+            })
+            .collect::<Html>();
+        let set_frame_rate = self.link.batch_callback(move |change: ChangeData| {
+            let frame_rate = *FRAME_RATES.get(Self::selected_index(change)?)?;
+            Some(Msg::SetCaptureSettings(CaptureSettings {
+                frame_rate,
+                ..settings
+            }))
+        });
+        let cursors = Self::view_options(&CURSORS, settings.cursor);
+        let set_cursor = self.link.batch_callback(move |change: ChangeData| {
+            let (_, cursor) = CURSORS.get(Self::selected_index(change)?)?;
+            let cursor = *cursor;
+            Some(Msg::SetCaptureSettings(CaptureSettings {
+                cursor,
+                ..settings
+            }))
+        });
+        let surfaces = Self::view_options(&SURFACES, settings.surface);
+        let set_surface = self.link.batch_callback(move |change: ChangeData| {
+            let (_, surface) = SURFACES.get(Self::selected_index(change)?)?;
+            let surface = *surface;
+            Some(Msg::SetCaptureSettings(CaptureSettings {
+                surface,
+                ..settings
+            }))
+        });
+        html! {
+            <div>
+                { presets }
+                <select class="uk-select uk-form-small" onchange={set_resolution}>{ resolutions }</select>
+                <select class="uk-select uk-form-small" onchange={set_frame_rate}>{ frame_rates }</select>
+                <select class="uk-select uk-form-small" onchange={set_cursor}>{ cursors }</select>
+                <select class="uk-select uk-form-small" onchange={set_surface}>{ surfaces }</select>
+            </div>
+        }
+    }
+
+    fn view_options(options: &[(&str, &str)], selected: &str) -> Html {
+        options
+            .iter()
+            .enumerate()
+            .map(|(index, (label, value))| {
+                html! { <option value={index.to_string()} selected={*value == selected}>{ label }</option> }
+            })
+            .collect::<Html>()
+    }
+
+    /// Option values are indices into the list they were rendered from.
+    fn selected_index(change: ChangeData) -> Option<usize> {
+        match change {
+            ChangeData::Select(select) => select.value().parse().ok(),
+            _ => None,
+        }
+    }
+
+    async fn init_camera() -> Result<MediaStream, JsValue> {
+        let media_device = web_sys::window()
+            .ok_or("no window found")?
+            .navigator()
+            .media_devices()?;
+        let mut constraints = MediaStreamConstraints::new();
+        constraints.audio(&JsValue::FALSE).video(&JsValue::TRUE);
+        let stream_promise = media_device.get_user_media_with_constraints(&constraints)?;
+        Ok(MediaStream::from(
+            wasm_bindgen_futures::JsFuture::from(stream_promise).await?,
+        ))
+    }
+
+    async fn init_microphone(media_device: &MediaDevices) -> Result<MediaStream, JsValue> {
+        let mut constraints = MediaStreamConstraints::new();
+        constraints.audio(&JsValue::TRUE).video(&JsValue::FALSE);
+        let stream_promise = media_device.get_user_media_with_constraints(&constraints)?;
+        Ok(MediaStream::from(
+            wasm_bindgen_futures::JsFuture::from(stream_promise).await?,
+        ))
+    }
+
+    fn view_sharing_controls(&self) -> Html {
+        let data = self.data.borrow();
+        if data.local_stream.is_none() {
+            return html! {};
+        }
+        let switch_source = self.link.callback(|_| Msg::SwitchSource);
+        let (switch_label, stop) = if data.display_tracks.is_empty() {
+            let paused =
+                html! { <span class="uk-label uk-label-warning">{"Sharing paused"}</span> };
+            ("Share again", paused)
+        } else {
+            let stop_sharing = self.link.callback(|_| Msg::StopSharing);
+            let stop = html! {
+                <button class="uk-button uk-button-danger" onclick={stop_sharing}>{"Stop sharing"}</button>
+            };
+            ("Share a different window", stop)
         };
-        video_element.set_src_object(Some(&media_stream));
-        Ok(media_stream)
+        html! {
+            <>
+                <button class="uk-button uk-button-default" onclick={switch_source}>{ switch_label }</button>
+                { stop }
+            </>
+        }
     }
 
-    fn open_web_socket(&self) -> Result<WebSocket, JsValue> {
+    /// Mute toggles for the captured tracks.
+    fn view_local_tracks(&self) -> Html {
+        let tracks = match self.data.borrow().local_stream.as_ref() {
+            Some(stream) => stream.get_tracks(),
+            None => return html! {},
+        };
+        tracks
+            .iter()
+            .map(|track| {
+                let track: MediaStreamTrack = track.unchecked_into();
+                let (label, class) = if track.enabled() {
+                    ("Mute", "uk-button uk-button-default uk-button-small")
+                } else {
+                    ("Unmute", "uk-button uk-button-danger uk-button-small")
+                };
+                let track_id = track.id();
+                let toggle = self
+                    .link
+                    .callback(move |_| Msg::ToggleTrack(track_id.clone()));
+                html! {
+                    <div>
+                        <span class="uk-text-small">{ track.kind() }{": "}{ track.label() }</span>
+                        <button class={class} onclick={toggle}>{ label }</button>
+                    </div>
+                }
+            })
+            .collect::<Html>()
+    }
+
+    fn open_web_socket(&self) -> Result<(), JsValue> {
         let ws = WebSocket::new(&self.data.borrow().server_socket)?;
         ws.set_binary_type(BinaryType::Arraybuffer);
 
@@ -266,25 +1278,767 @@ impl Model {
         ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
         on_error.forget();
 
-        let on_open = Closure::wrap(Box::new(|_| {
+        let on_close = self.link.callback(|_| Msg::WebSocketClosed);
+        let on_close =
+            Closure::wrap(Box::new(move |event: CloseEvent| on_close.emit(event))
+                as Box<dyn FnMut(CloseEvent)>);
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        on_close.forget();
+
+        let data = self.data.clone();
+        let on_open = Closure::wrap(Box::new(move |_| {
             log("ws: opened");
+            let data = data.borrow();
+            let resume = match (data.user_id.as_ref(), data.resume_token.as_ref()) {
+                (Some(user_id), Some(resume_token)) => {
+                    Some((user_id.clone(), resume_token.clone()))
+                }
+                _ => None,
+            };
+            let hello = Signal::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client_name: format!("frontend {}", env!("CARGO_PKG_VERSION")),
+                capabilities: vec![
+                    Capability::MultipleViewers,
+                    Capability::EndOfCandidates,
+                    Capability::Resume,
+                ],
+                resume,
+            };
+            if let Err(e) = data.signalling.send_now(&hello) {
+                log_error(&format!("error sending Hello: {:?}", e));
+            }
         }) as Box<dyn FnMut(JsValue)>);
         ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
         on_open.forget();
 
-        Ok(ws)
+        self.data.borrow().signalling.set_web_socket(ws);
+        Ok(())
     }
 
-    fn get_session_to_connect() -> Result<String, JsValue> {
+    fn get_input_value(id: &str) -> Result<String, JsValue> {
         let window = web_sys::window().ok_or("no window found")?;
         let doc = window.document().ok_or("no doc found")?;
-        let video_element = doc
-            .get_element_by_id("session_to_connect")
-            .expect("no session_to_connect element");
-        let input = video_element.dyn_into::<HtmlInputElement>()?;
+        let element = doc
+            .get_element_by_id(id)
+            .ok_or_else(|| format!("no {} element", id))?;
+        let input = element.dyn_into::<HtmlInputElement>()?;
         Ok(input.value())
     }
 
+    /// Messages consisting of a single link are made clickable.
+    fn view_chat_message(author: &str, text: &str) -> Html {
+        let is_link = (text.starts_with("https://") || text.starts_with("http://"))
+            && !text.contains(char::is_whitespace);
+        let text = if is_link {
+            html! { <a href={text.to_string()} target="_blank" rel="noopener noreferrer">{ text }</a> }
+        } else {
+            html! { <span>{ text }</span> }
+        };
+        html! {
+            <li><span class="uk-text-bold">{ author }{": "}</span>{ text }</li>
+        }
+    }
+
+    fn view_participant(&self, data: &ModelData, user_id: &UserId, display_name: &str) -> Html {
+        let role = data.roles.get(user_id).cloned();
+        let role_label = match role {
+            Some(role) => format!("{:?}", role),
+            None => String::new(),
+        };
+        let present = if role != Some(Role::Presenter)
+            && (data.can_moderate() || data.is_presenting())
+        {
+            let peer_id = user_id.clone();
+            let present = self
+                .link
+                .callback(move |_| Msg::SetPresenter(peer_id.clone()));
+            html! {
+                <button class="uk-button uk-button-default uk-button-small" onclick={present}>{"Make presenter"}</button>
+            }
+        } else {
+            html! {}
+        };
+        let co_host = if data.is_host() && role != Some(Role::Presenter) {
+            let peer_id = user_id.clone();
+            let is_co_host = role == Some(Role::CoHost);
+            let toggle = self
+                .link
+                .callback(move |_| Msg::SetCoHost(peer_id.clone(), !is_co_host));
+            let label = if is_co_host {
+                "Revoke co-host"
+            } else {
+                "Make co-host"
+            };
+            html! {
+                <button class="uk-button uk-button-default uk-button-small" onclick={toggle}>{ label }</button>
+            }
+        } else {
+            html! {}
+        };
+        // The server never removes the host
+        let moderation = if data.can_moderate() && data.host.as_ref() != Some(user_id) {
+            let kick_id = user_id.clone();
+            let kick = self.link.callback(move |_| Msg::Kick(kick_id.clone()));
+            let ban_id = user_id.clone();
+            let ban = self.link.callback(move |_| Msg::Ban(ban_id.clone()));
+            html! {
+                <>
+                    <button class="uk-button uk-button-default uk-button-small" onclick={kick}>{"Kick"}</button>
+                    <button class="uk-button uk-button-danger uk-button-small" onclick={ban}>{"Ban"}</button>
+                </>
+            }
+        } else {
+            html! {}
+        };
+        let recovery = match data.ice_recovery.get(user_id) {
+            Some(recovery @ IceRecovery::Lost) => html! {
+                <span class="uk-label uk-label-danger">{ recovery }</span>
+            },
+            Some(recovery) => html! {
+                <span class="uk-label uk-label-warning">{ recovery }</span>
+            },
+            None => html! {},
+        };
+        html! {
+            <li>
+                <span>{ display_name }</span>
+                <span class="uk-label">{ role_label }</span>
+                { recovery }
+                { present }
+                { co_host }
+                { moderation }
+            </li>
+        }
+    }
+
+    /// Input of a viewer in control is captured on the presenter's video.
+    fn view_external_video(&self) -> Html {
+        let in_control = self.data.borrow().remote_control.status() == ControlStatus::Granted;
+        let pointer_move = self.link.batch_callback(move |event: MouseEvent| {
+            let (x, y) = Self::event_stream_position(&event).filter(|_| in_control)?;
+            Some(Msg::SendRemoteInput(RemoteInput::PointerMove { x, y }))
+        });
+        let pointer_down = self.link.batch_callback(move |event: MouseEvent| {
+            let (x, y) = Self::event_stream_position(&event).filter(|_| in_control)?;
+            event.prevent_default();
+            let button = event.button().into();
+            Some(Msg::SendRemoteInput(RemoteInput::PointerDown {
+                x,
+                y,
+                button,
+            }))
+        });
+        let pointer_up = self.link.batch_callback(move |event: MouseEvent| {
+            let (x, y) = Self::event_stream_position(&event).filter(|_| in_control)?;
+            event.prevent_default();
+            let button = event.button().into();
+            Some(Msg::SendRemoteInput(RemoteInput::PointerUp {
+                x,
+                y,
+                button,
+            }))
+        });
+        let wheel = self.link.batch_callback(move |event: WheelEvent| {
+            let (x, y) = Self::event_stream_position(&event).filter(|_| in_control)?;
+            event.prevent_default();
+            Some(Msg::SendRemoteInput(RemoteInput::Wheel {
+                x,
+                y,
+                delta_x: event.delta_x(),
+                delta_y: event.delta_y(),
+            }))
+        });
+        let context_menu = self.link.batch_callback(move |event: MouseEvent| {
+            if in_control {
+                event.prevent_default();
+            }
+            None
+        });
+        let key_down = self.link.batch_callback(move |event: KeyboardEvent| {
+            if !in_control {
+                return None;
+            }
+            event.prevent_default();
+            Some(Msg::SendRemoteInput(RemoteInput::KeyDown {
+                key: event.key(),
+                code: event.code(),
+                modifiers: Self::key_modifiers(&event),
+            }))
+        });
+        let key_up = self.link.batch_callback(move |event: KeyboardEvent| {
+            if !in_control {
+                return None;
+            }
+            event.prevent_default();
+            Some(Msg::SendRemoteInput(RemoteInput::KeyUp {
+                key: event.key(),
+                code: event.code(),
+                modifiers: Self::key_modifiers(&event),
+            }))
+        });
+        let drag_tile = self.link.batch_callback(|event: MouseEvent| {
+            if event.buttons() == 0 {
+                return None;
+            }
+            Some(Msg::CameraTileDrag(event.client_x(), event.client_y()))
+        });
+        let drop_tile = self.link.callback(|_| Msg::CameraTileDragEnd);
+        let leave_tile = self.link.callback(|_| Msg::CameraTileDragEnd);
+        html! {
+            <div class="uk-inline" onmousemove={drag_tile} onmouseup={drop_tile} onmouseleave={leave_tile}>
+                <video id="external_video" width="320" height="240" style="color: black; outline-style: solid;" autoplay=true tabindex="0"
+                    onmousemove={pointer_move} onmousedown={pointer_down} onmouseup={pointer_up} onwheel={wheel}
+                    oncontextmenu={context_menu} onkeydown={key_down} onkeyup={key_up}></video>
+                { self.view_annotation_canvas("external_annotations", "external_video") }
+                <div id="external_paused" class="uk-overlay uk-overlay-primary uk-position-cover uk-flex uk-flex-center uk-flex-middle" hidden=true>
+                    {"Sharing paused"}
+                </div>
+                { self.view_camera_tile() }
+            </div>
+        }
+    }
+
+    /// Shows where the viewer in control points on the shared screen.
+    fn view_local_video(&self) -> Html {
+        let cursor = self
+            .data
+            .borrow()
+            .remote_control
+            .cursor()
+            .and_then(|(x, y)| {
+                let video = video_element("local_video")?;
+                let (left, top, width, height) = video_content_box(&video)?;
+                Some((left + x * width, top + y * height))
+            });
+        let cursor = match cursor {
+            Some((left, top)) => {
+                let style = format!(
+                    "position: absolute; left: {}px; top: {}px; width: 12px; height: 12px; \
+                     margin: -6px 0 0 -6px; border-radius: 50%; background: red; \
+                     pointer-events: none;",
+                    left, top
+                );
+                html! { <div style={style}></div> }
+            }
+            None => html! {},
+        };
+        html! {
+            <div class="uk-inline">
+                <video id="local_video" width="320" height="240" style="color: black; outline-style: solid;" autoplay=true></video>
+                { self.view_annotation_canvas("local_annotations", "local_video") }
+                <video id="local_camera" width={CAMERA_TILE_SIZE.0.to_string()} height={CAMERA_TILE_SIZE.1.to_string()} autoplay=true hidden=true
+                    style="position: absolute; right: 8px; bottom: 8px; background: black;"></video>
+                { cursor }
+            </div>
+        }
+    }
+
+    /// The presenter's camera, dragged around with the pointer. Moves are
+    /// tracked on the surrounding element so fast moves do not lose the tile.
+    fn view_camera_tile(&self) -> Html {
+        let (left, top) = self.data.borrow().camera_tile;
+        let style = format!(
+            "position: absolute; left: {}px; top: {}px; cursor: move; background: black;",
+            left, top
+        );
+        let drag_start = self.link.callback(|event: MouseEvent| {
+            event.prevent_default();
+            Msg::CameraTileDragStart(event.client_x(), event.client_y())
+        });
+        html! {
+            <video id="external_camera" width={CAMERA_TILE_SIZE.0.to_string()} height={CAMERA_TILE_SIZE.1.to_string()} autoplay=true hidden=true
+                style={style} onmousedown={drag_start}></video>
+        }
+    }
+
+    /// Covers the video and only takes pointer input while annotating.
+    fn view_annotation_canvas(&self, canvas_id: &'static str, video_id: &'static str) -> Html {
+        let pointer_events = match self.data.borrow().annotation_tool {
+            Some(_) => "auto; cursor: crosshair",
+            None => "none",
+        };
+        let style = format!(
+            "position: absolute; top: 0; left: 0; pointer-events: {};",
+            pointer_events
+        );
+        let start = self.link.batch_callback(move |event: MouseEvent| {
+            stream_position(&video_element(video_id)?, &event).map(Msg::AnnotationStart)
+        });
+        let extend = self.link.batch_callback(move |event: MouseEvent| {
+            stream_position(&video_element(video_id)?, &event).map(Msg::AnnotationMove)
+        });
+        let finish = self.link.callback(|_| Msg::AnnotationEnd);
+        let leave = self.link.callback(|_| Msg::AnnotationEnd);
+        html! {
+            <canvas id={canvas_id} width="320" height="240" style={style}
+                onmousedown={start} onmousemove={extend} onmouseup={finish} onmouseleave={leave}></canvas>
+        }
+    }
+
+    fn view_annotation_tools(&self) -> Html {
+        let data = self.data.borrow();
+        if data.session_id.value().is_empty() {
+            return html! {};
+        }
+        let tools = [
+            ("Draw", Some(AnnotationKind::Stroke)),
+            ("Arrow", Some(AnnotationKind::Arrow)),
+            ("Highlight", Some(AnnotationKind::Highlight)),
+            ("Stop annotating", None),
+        ];
+        let tools = tools
+            .iter()
+            .map(|(label, tool)| {
+                let tool = *tool;
+                let class = if data.annotation_tool == tool {
+                    "uk-button uk-button-primary uk-button-small"
+                } else {
+                    "uk-button uk-button-default uk-button-small"
+                };
+                let select = self.link.callback(move |_| Msg::SelectAnnotationTool(tool));
+                html! { <button class={class} onclick={select}>{ label }</button> }
+            })
+            .collect::<Html>();
+        let clear = if data.is_host() {
+            let clear = self.link.callback(|_| Msg::ClearAnnotations);
+            html! {
+                <button class="uk-button uk-button-danger uk-button-small" onclick={clear}>{"Clear annotations"}</button>
+            }
+        } else {
+            html! {}
+        };
+        html! {
+            <div>
+                { tools }
+                { clear }
+            </div>
+        }
+    }
+
+    fn view_remote_control(&self) -> Html {
+        let data = self.data.borrow();
+        if data.session_id.value().is_empty() {
+            return html! {};
+        }
+        if !data.is_presenting() {
+            return match data.remote_control.status() {
+                ControlStatus::Requested => html! {
+                    <span class="uk-label">{"Control requested"}</span>
+                },
+                ControlStatus::Granted => {
+                    let release = self.link.callback(|_| Msg::ReleaseControl);
+                    html! {
+                        <>
+                            <span class="uk-label uk-label-success">{"You are in control"}</span>
+                            <button class="uk-button uk-button-default uk-button-small" onclick={release}>{"Release control"}</button>
+                        </>
+                    }
+                }
+                status => {
+                    let request = self.link.callback(|_| Msg::RequestControl);
+                    let denied = if status == ControlStatus::Denied {
+                        html! { <span class="uk-label uk-label-warning">{"Control denied"}</span> }
+                    } else {
+                        html! {}
+                    };
+                    html! {
+                        <>
+                            { denied }
+                            <button class="uk-button uk-button-default uk-button-small" onclick={request}>{"Request control"}</button>
+                        </>
+                    }
+                }
+            };
+        }
+        let requests = data
+            .remote_control
+            .requests()
+            .into_iter()
+            .map(|peer_id| {
+                let display_name = data.display_name(&peer_id);
+                let grant_id = peer_id.clone();
+                let grant = self.link.callback(move |_| Msg::GrantControl(grant_id.clone()));
+                let deny = self.link.callback(move |_| Msg::DenyControl(peer_id.clone()));
+                html! {
+                    <div class="uk-alert-primary" uk-alert="">
+                        <span>{ display_name }{" requests control"}</span>
+                        <button class="uk-button uk-button-primary uk-button-small" onclick={grant}>{"Allow"}</button>
+                        <button class="uk-button uk-button-danger uk-button-small" onclick={deny}>{"Deny"}</button>
+                    </div>
+                }
+            })
+            .collect::<Html>();
+        let controller = match data.remote_control.controller() {
+            Some(controller) => {
+                let revoke = self.link.callback(|_| Msg::RevokeControl);
+                let events = data
+                    .remote_control
+                    .events()
+                    .into_iter()
+                    .map(|event| html! { <li>{ event }</li> })
+                    .collect::<Html>();
+                html! {
+                    <>
+                        <span class="uk-label uk-label-warning">{ data.display_name(&controller) }{" is in control"}</span>
+                        <button class="uk-button uk-button-default uk-button-small" onclick={revoke}>{"Revoke control"}</button>
+                        <ul class="uk-list uk-list-collapse uk-text-small">{ events }</ul>
+                    </>
+                }
+            }
+            None => html! {},
+        };
+        html! {
+            <>
+                { requests }
+                { controller }
+            </>
+        }
+    }
+
+    /// Normalized position on the stream of the video the event targets.
+    fn event_stream_position(event: &MouseEvent) -> Option<(f64, f64)> {
+        let video = event.target()?.dyn_into::<HtmlVideoElement>().ok()?;
+        stream_position(&video, event)
+    }
+
+    fn key_modifiers(event: &KeyboardEvent) -> KeyModifiers {
+        KeyModifiers {
+            shift: event.shift_key(),
+            control: event.ctrl_key(),
+            alt: event.alt_key(),
+            meta: event.meta_key(),
+        }
+    }
+
+    fn view_transfer(&self, transfer: &FileTransfer) -> Html {
+        let peer_name = self.data.borrow().display_name(&transfer.peer_id);
+        let direction = match transfer.direction {
+            TransferDirection::Sending => "to",
+            TransferDirection::Receiving => "from",
+        };
+        let status = match &transfer.state {
+            TransferState::InProgress => {
+                let peer_id = transfer.peer_id.clone();
+                let (id, direction) = (transfer.id, transfer.direction);
+                let cancel = self
+                    .link
+                    .callback(move |_| Msg::CancelTransfer(peer_id.clone(), id, direction));
+                html! {
+                    <>
+                        <progress class="uk-progress" value={transfer.transferred.to_string()} max={transfer.size.to_string()}></progress>
+                        <button class="uk-button uk-button-default uk-button-small" onclick={cancel}>{"Cancel"}</button>
+                    </>
+                }
+            }
+            TransferState::Sent => {
+                html! { <span class="uk-label uk-label-success">{"Sent"}</span> }
+            }
+            TransferState::Verified(url) => html! {
+                <a class="uk-button uk-button-primary uk-button-small" href={url.clone()} download={transfer.name.clone()}>{"Save"}</a>
+            },
+            TransferState::Cancelled => html! { <span class="uk-label">{"Cancelled"}</span> },
+            TransferState::Failed(reason) => html! {
+                <span class="uk-label uk-label-danger">{"Failed: "}{ reason }</span>
+            },
+        };
+        html! {
+            <li>
+                <span>{ &transfer.name }{" ("}{ transfer.size }{" bytes) "}{ direction }{" "}{ peer_name }</span>
+                { status }
+            </li>
+        }
+    }
+
+    /// Sends a moderator action about the peer. Its join request is answered
+    /// either way, a participant stays until the server reports it left.
+    fn send_peer_action(&self, peer_id: &UserId, action: Signal) {
+        let mut data = self.data.borrow_mut();
+        data.join_requests.retain(|(user_id, _)| user_id != peer_id);
+        if let Err(e) = data.signalling.send(action) {
+            log_error(&format!("error sending action for {:?}: {:?}", peer_id, e));
+        }
+    }
+
+    /// Session code of the `#/join/<code>` invite link the page was opened
+    /// with, if any.
+    fn join_code_from_location() -> Option<SessionId> {
+        let hash = web_sys::window()?.location().hash().ok()?;
+        match hash.strip_prefix("#/join/") {
+            Some(code) if !code.is_empty() => Some(SessionId::new(code.to_string())),
+            _ => None,
+        }
+    }
+
+    fn invite_link(session_id: &SessionId) -> Result<String, JsValue> {
+        let location = web_sys::window().ok_or("no window found")?.location();
+        Ok(format!(
+            "{}{}#/join/{}",
+            location.origin()?,
+            location.pathname()?,
+            session_id.value()
+        ))
+    }
+
+    /// `Navigator::clipboard` is only available with web-sys' unstable APIs,
+    /// so the Clipboard API is called through reflection.
+    async fn copy_to_clipboard(text: String) -> Result<(), JsValue> {
+        let navigator = web_sys::window().ok_or("no window found")?.navigator();
+        let clipboard = js_sys::Reflect::get(&navigator, &JsValue::from_str("clipboard"))?;
+        let write_text = js_sys::Reflect::get(&clipboard, &JsValue::from_str("writeText"))?
+            .dyn_into::<js_sys::Function>()?;
+        let promise = write_text
+            .call1(&clipboard, &JsValue::from_str(&text))?
+            .dyn_into::<js_sys::Promise>()?;
+        wasm_bindgen_futures::JsFuture::from(promise).await?;
+        log("copied invite link");
+        Ok(())
+    }
+
+    fn is_checked(id: &str) -> bool {
+        web_sys::window()
+            .and_then(|window| window.document())
+            .and_then(|doc| doc.get_element_by_id(id))
+            .and_then(|element| element.dyn_into::<HtmlInputElement>().ok())
+            .map(|input| input.checked())
+            .unwrap_or(false)
+    }
+
+    fn clear_input(id: &str) {
+        let input = web_sys::window()
+            .and_then(|window| window.document())
+            .and_then(|doc| doc.get_element_by_id(id))
+            .and_then(|element| element.dyn_into::<HtmlInputElement>().ok());
+        if let Some(input) = input {
+            input.set_value("");
+        }
+    }
+
+    fn get_session_to_connect() -> Result<String, JsValue> {
+        Self::get_input_value("session_to_connect")
+    }
+
+    /// An empty password field means the session is not protected.
+    fn get_session_password() -> Option<String> {
+        match Self::get_input_value("session_password") {
+            Ok(password) if !password.is_empty() => Some(password),
+            _ => None,
+        }
+    }
+
+    /// Invite links join before a name could be entered, so it is asked for
+    /// unless the field is already filled.
+    fn prompt_display_name() -> String {
+        match Self::get_input_value("display_name") {
+            Ok(name) if !name.is_empty() => name,
+            _ => web_sys::window()
+                .and_then(|window| window.prompt_with_message("Your name").ok())
+                .flatten()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the handle of the timer.
+    fn send_message_later(&self, msg: Msg, delay: i32) -> Result<i32, JsValue> {
+        let link = self.link.clone();
+        let on_timeout = Closure::once_into_js(move || link.send_message(msg));
+        web_sys::window()
+            .unwrap_throw()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                on_timeout.unchecked_ref(),
+                delay,
+            )
+    }
+
+    fn show_local_camera(stream: Option<&MediaStream>) {
+        if let Some(camera) = video_element("local_camera") {
+            camera.set_src_object(stream);
+            camera.set_hidden(stream.is_none());
+        }
+    }
+
+    fn create_peer_connection(
+        data: &Rc<RefCell<ModelData>>,
+        session_id: SessionId,
+        peer_id: UserId,
+    ) -> Result<Negotiation, JsValue> {
+        let connection = RtcPeerConnection::new()?;
+        // Every participant is introduced before it can offer, so the role
+        // is only missing for a peer that already left
+        let role = data
+            .borrow()
+            .negotiation_roles
+            .get(&peer_id)
+            .copied()
+            .unwrap_or(NegotiationRole::Polite);
+        let negotiation = Negotiation::new(
+            &connection,
+            session_id.clone(),
+            peer_id.clone(),
+            data.borrow().signalling.clone(),
+            role,
+        );
+        // Viewers make the first offer once their transceivers and data
+        // channels ask for a negotiation
+        let is_presenting = data.borrow().is_presenting();
+        Self::setup_data_channels(data, &connection, peer_id.clone(), !is_presenting);
+        // The presenter's transceivers come with the viewer's offer
+        if !is_presenting {
+            add_receiving_transceivers(&connection);
+        }
+        data.borrow()
+            .remote_media
+            .setup(&connection, peer_id.clone());
+        let state_peer_id = peer_id.clone();
+        let on_state_change = data
+            .borrow()
+            .ice_state_changed
+            .reform(move |state| (state_peer_id.clone(), state));
+        setup_rtc_connection_ice(
+            &connection,
+            session_id,
+            peer_id.clone(),
+            data.borrow().signalling.clone(),
+            on_state_change,
+        );
+        data.borrow_mut().peers.insert(peer_id, negotiation.clone());
+        Ok(negotiation)
+    }
+
+    /// The offering side creates the data channels, the answering side picks
+    /// them up by label.
+    fn setup_data_channels(
+        data: &Rc<RefCell<ModelData>>,
+        connection: &RtcPeerConnection,
+        peer_id: UserId,
+        is_offerer: bool,
+    ) {
+        if is_offerer {
+            for label in DATA_CHANNEL_LABELS.iter() {
+                let channel = connection.create_data_channel(label);
+                Self::add_data_channel(&data.borrow(), &channel, peer_id.clone());
+            }
+            return;
+        }
+        let data = data.clone();
+        let on_data_channel = Closure::wrap(Box::new(move |event: RtcDataChannelEvent| {
+            Self::add_data_channel(&data.borrow(), &event.channel(), peer_id.clone());
+        }) as Box<dyn FnMut(RtcDataChannelEvent)>);
+        connection.set_ondatachannel(Some(on_data_channel.as_ref().unchecked_ref()));
+        on_data_channel.forget();
+    }
+
+    fn add_data_channel(data: &ModelData, channel: &RtcDataChannel, peer_id: UserId) {
+        match channel.label().as_str() {
+            CHAT_CHANNEL_LABEL => data.chat_channels.add_channel(channel, peer_id),
+            FILE_CHANNEL_LABEL => data.file_transfers.add_channel(channel, peer_id),
+            CONTROL_CHANNEL_LABEL => data.remote_control.add_channel(channel, peer_id),
+            ANNOTATION_CHANNEL_LABEL => {
+                // The presenter relays clearing to everyone, only the host may
+                let may_clear = !data.is_presenting() || data.host.as_ref() == Some(&peer_id);
+                data.annotations.add_channel(channel, peer_id, may_clear)
+            }
+            label => log_error(&format!("unexpected data channel {:?}", label)),
+        }
+    }
+
+    /// Ends the screen capture and takes its tracks out of the local stream.
+    fn stop_display_tracks(&self) {
+        let mut data = self.data.borrow_mut();
+        let tracks = std::mem::take(&mut data.display_tracks);
+        for track in tracks.iter() {
+            // Its handler must not end the capture replacing it
+            track.set_onended(None);
+            track.stop();
+            if let Some(stream) = data.local_stream.as_ref() {
+                stream.remove_track(track);
+            }
+        }
+    }
+
+    /// Ends the screen capture and the microphone.
+    fn stop_local_stream(&self) {
+        self.stop_display_tracks();
+        if let Some(stream) = self.data.borrow_mut().local_stream.take() {
+            for track in stream.get_tracks().iter() {
+                track.unchecked_into::<MediaStreamTrack>().stop();
+            }
+        }
+    }
+
+    /// The browser can end the capture on its own, e.g. through its "stop
+    /// sharing" button.
+    fn handle_display_ended(&self, tracks: &[MediaStreamTrack]) {
+        for track in tracks.iter().filter(|track| track.kind() == "video") {
+            let on_ended = self.link.callback(|_: ()| Msg::StopSharing);
+            let on_ended = Closure::once_into_js(move || on_ended.emit(()));
+            track.set_onended(Some(on_ended.unchecked_ref()));
+        }
+    }
+
+    fn update_senders_in_background(&self) {
+        if !self.data.borrow().is_presenting() {
+            return;
+        }
+        let data = self.data.clone();
+        self.link.send_future(async move {
+            match Self::update_all_senders(data).await {
+                Ok(()) => Msg::EventHandled(false),
+                Err(e) => Msg::EventError(e),
+            }
+        });
+    }
+
+    /// Puts the current local tracks on every peer connection. Transceivers
+    /// starting or stopping to send renegotiate on their own.
+    async fn update_all_senders(data: Rc<RefCell<ModelData>>) -> Result<(), JsValue> {
+        let connections: Vec<RtcPeerConnection> = data
+            .borrow()
+            .peers
+            .values()
+            .map(|negotiation| negotiation.connection().clone())
+            .collect();
+        for connection in connections {
+            Self::update_senders(&data, &connection).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends each local track on the transceiver of its purpose, tracks are
+    /// switched through `replaceTrack`.
+    async fn update_senders(
+        data: &Rc<RefCell<ModelData>>,
+        connection: &RtcPeerConnection,
+    ) -> Result<(), JsValue> {
+        for purpose in TRACK_PURPOSES.iter() {
+            let transceiver = match purpose_transceiver(connection, *purpose) {
+                Some(transceiver) => transceiver,
+                None => continue,
+            };
+            let track = data.borrow().local_track(*purpose);
+            let promise = transceiver.sender().replace_track(track.as_ref());
+            wasm_bindgen_futures::JsFuture::from(promise).await?;
+            let direction = match track {
+                Some(_) => RtcRtpTransceiverDirection::Sendonly,
+                None => RtcRtpTransceiverDirection::Inactive,
+            };
+            if transceiver.direction() != direction {
+                transceiver.set_direction(direction);
+            }
+        }
+        Ok(())
+    }
+
+    fn get_negotiation(
+        data: &Rc<RefCell<ModelData>>,
+        peer_id: &UserId,
+    ) -> Result<Negotiation, JsValue> {
+        match data.borrow().peers.get(peer_id) {
+            Some(negotiation) => Ok(negotiation.clone()),
+            None => Err(format!("no connection to peer {:?}", peer_id).into()),
+        }
+    }
+
     async fn handle_message(
         data: Rc<RefCell<ModelData>>,
         message: String,
@@ -295,94 +2049,210 @@ impl Model {
         };
         log(&format!("hadling signal: {:?}", signal));
         let res = match signal {
-            Signal::NewUser(user_id) => {
-                log(&format!("new user id: {:?}", user_id));
+            Signal::Welcome {
+                protocol_version,
+                features,
+                limits,
+            } => {
+                log(&format!(
+                    "connected to server v{} with features {:?} and limits {:?}",
+                    protocol_version, features, limits
+                ));
                 false
             }
+            Signal::NewUser(user_id, resume_token) => {
+                log(&format!("new user id: {:?}", user_id));
+                let mut data = data.borrow_mut();
+                if data.user_id.is_some() && data.user_id.as_ref() != Some(&user_id) {
+                    // The server could not resume the previous user, so
+                    // neither the session nor the queued signals are valid
+                    data.signalling.clear_pending();
+                    data.reset_session();
+                }
+                data.user_id = Some(user_id);
+                data.resume_token = Some(resume_token);
+                data.reconnect_attempts = 0;
+                data.signalling.set_ready()?;
+                true
+            }
+            Signal::Resumed(session_id) => {
+                let mut data = data.borrow_mut();
+                match session_id {
+                    Some(session_id) if session_id == data.session_id => {
+                        data.session_status = SessionStatus::Connected;
+                    }
+                    _ => data.reset_session(),
+                }
+                true
+            }
             Signal::SessionCreated(session_id) => {
-                data.borrow_mut().session_id = session_id.clone();
-
-                setup_rtc_connection_ice(
-                    &data.borrow().rtc_connection,
-                    session_id,
-                    data.borrow().web_socket.as_ref().unwrap().clone(),
-                );
+                let mut data = data.borrow_mut();
+                data.reset_session();
+                data.session_id = session_id;
+                data.host = data.user_id.clone();
+                if let Some(user_id) = data.user_id.clone() {
+                    data.roles.insert(user_id, Role::Presenter);
+                }
+                data.session_status = SessionStatus::Connected;
+                data.error = None;
                 true
             }
-            Signal::SessionJoinSuccess(session_id) => {
-                data.borrow_mut().session_status = SessionStatus::Connected;
-
-                setup_rtc_connection_ice(
-                    &data.borrow().rtc_connection,
-                    session_id.clone(),
-                    data.borrow().web_socket.as_ref().unwrap().clone(),
-                );
-
-                let offer = create_sdp_offer(&data.borrow().rtc_connection).await?;
-
-                let msg = Signal::VideoOffer(session_id, offer.into());
-                let result: String = match serde_json::to_string(&msg) {
-                    Ok(x) => x,
-                    Err(e) => return Err(e.to_string().into()),
-                };
-
-                match data
-                    .borrow()
-                    .web_socket
-                    .as_ref()
-                    .unwrap()
-                    .send_with_str(&result)
-                {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
+            Signal::SessionJoinPending(session_id) => {
+                let mut data = data.borrow_mut();
+                data.reset_session();
+                data.session_id = session_id;
+                data.session_status = SessionStatus::Waiting;
+                data.error = None;
+                true
+            }
+            Signal::SessionJoinSuccess(session_id, host) => {
+                let mut data = data.borrow_mut();
+                data.reset_session();
+                data.session_id = session_id;
+                data.host = Some(host);
+                if let Some(user_id) = data.user_id.clone() {
+                    data.roles.insert(user_id, Role::Viewer);
                 }
-
+                data.session_status = SessionStatus::Connected;
+                data.error = None;
                 true
             }
             Signal::SessionJoinError(_) => {
                 data.borrow_mut().session_status = SessionStatus::Error;
                 true
             }
-            Signal::VideoOffer(_, offer) => {
-                let answer = create_sdp_answer(&data.borrow().rtc_connection, &offer).await?;
+            Signal::PeerJoined(_, peer_id, display_name, negotiation_role) => {
+                // The newcomer is a viewer and connects to the presenter
+                let mut data = data.borrow_mut();
+                data.join_requests
+                    .retain(|(user_id, _)| *user_id != peer_id);
+                data.participants.retain(|(user_id, _)| *user_id != peer_id);
+                data.participants.push((peer_id.clone(), display_name));
+                data.negotiation_roles
+                    .insert(peer_id.clone(), negotiation_role);
+                data.roles.insert(peer_id, Role::Viewer);
+                true
+            }
+            Signal::RoleChanged(session_id, peer_id, role) => {
+                data.borrow_mut().roles.insert(peer_id.clone(), role);
+                if role != Role::Presenter {
+                    return Ok(true);
+                }
 
-                let msg = Signal::VideoAnswer(data.borrow().session_id.clone(), answer.into());
-                let result: String = match serde_json::to_string(&msg) {
-                    Ok(x) => x,
-                    Err(e) => return Err(e.to_string().into()),
+                // Connections to the previous presenter are of no use now
+                let peers: Vec<(UserId, Negotiation)> = {
+                    let mut data = data.borrow_mut();
+                    data.stop_ice_recovery();
+                    data.peers.drain().collect()
                 };
-
-                match data
-                    .borrow()
-                    .web_socket
-                    .as_ref()
-                    .unwrap()
-                    .send_with_str(&result)
-                {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
+                for (peer_id, negotiation) in peers.iter() {
+                    data.borrow().close_peer(peer_id, negotiation);
+                }
+                data.borrow().remote_control.clear();
+                data.borrow().remote_media.clear();
+                if data.borrow().user_id.as_ref() != Some(&peer_id) {
+                    Self::create_peer_connection(&data, session_id, peer_id)?;
+                }
+                true
+            }
+            Signal::SessionEnded(_) => {
+                data.borrow_mut().reset_session();
+                true
+            }
+            Signal::PeerLeft(_, peer_id) => {
+                let negotiation = {
+                    let mut data = data.borrow_mut();
+                    data.join_requests
+                        .retain(|(user_id, _)| *user_id != peer_id);
+                    data.participants.retain(|(user_id, _)| *user_id != peer_id);
+                    data.roles.remove(&peer_id);
+                    data.negotiation_roles.remove(&peer_id);
+                    if let Some(recovery) = data.ice_recovery.remove(&peer_id) {
+                        recovery.cancel();
+                    }
+                    data.peers.remove(&peer_id)
+                };
+                if let Some(negotiation) = negotiation {
+                    data.borrow().close_peer(&peer_id, &negotiation);
                 }
+                true
+            }
+            Signal::JoinRequest(peer_id, display_name) => {
+                data.borrow_mut()
+                    .join_requests
+                    .push((peer_id, display_name));
+                true
+            }
+            Signal::VideoOffer(session_id, peer_id, offer) => {
+                let existing = data.borrow().peers.get(&peer_id).cloned();
+                let negotiation = match existing {
+                    Some(negotiation) => negotiation,
+                    None => {
+                        Self::create_peer_connection(&data, session_id.clone(), peer_id.clone())?
+                    }
+                };
+                if !negotiation.handle_offer(&offer).await? {
+                    return Ok(false);
+                }
+                // Transceivers of a first offer only exist from here on
+                let connection = negotiation.connection();
+                if data.borrow().is_presenting() {
+                    Self::update_senders(&data, connection).await?;
+                }
+                let answer = create_sdp_answer(connection).await?;
 
+                let msg = Signal::VideoAnswer(session_id, peer_id, answer);
+                data.borrow().signalling.send(msg)?;
+                true
+            }
+            Signal::VideoAnswer(_, peer_id, answer) => {
+                let negotiation = Self::get_negotiation(&data, &peer_id)?;
+                negotiation.handle_answer(&answer).await?;
                 true
             }
-            Signal::VideoAnswer(_, answer) => {
-                handle_sdp_answer(&data.borrow().rtc_connection, &answer).await?;
+            Signal::ICECandidate(_, peer_id, candidate) => {
+                let negotiation = Self::get_negotiation(&data, &peer_id)?;
+                negotiation.handle_candidate(candidate.as_ref()).await?;
                 true
             }
-            Signal::ICECandidate(_, candidate) => {
-                handle_ice_candidate(&data.borrow().rtc_connection, &candidate).await?;
+            Signal::Chat(_, peer_id, text) => {
+                let mut data = data.borrow_mut();
+                let author = data.display_name(&peer_id);
+                data.chat.push((author, text));
                 true
             }
             Signal::ICEError(_, error) => {
                 log_error(&format!("ice error: {}", error));
                 false
             }
+            Signal::Error(error) => {
+                log_error(&format!("signalling error: {}", error));
+                let mut data = data.borrow_mut();
+                match error {
+                    SignalError::JoinDenied(_)
+                    | SignalError::Kicked(_)
+                    | SignalError::Banned(_) => data.reset_session(),
+                    SignalError::IncompatibleProtocol { .. } => {
+                        data.reset_session();
+                        data.signalling.clear_pending();
+                        data.session_status = SessionStatus::Error;
+                    }
+                    _ => {}
+                }
+                data.error = Some(error);
+                true
+            }
             _ => return Err("received invalid signal".into()),
         };
         Ok(res)
     }
 }
 
+/// Delay before the next of repeated attempts, doubling up to a limit.
+fn backoff_delay(attempts: u32) -> i32 {
+    (1000 << attempts.min(5)).min(MAX_RECONNECT_DELAY_MS)
+}
+
 fn main() {
     yew::start_app::<Model>();
 }
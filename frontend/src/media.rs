@@ -0,0 +1,179 @@
+use crate::log;
+use crate::video::video_element;
+use signalling_protocol::UserId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    HtmlElement, MediaStream, MediaStreamTrack, MediaStreamTrackState, RtcPeerConnection,
+    RtcRtpTransceiver, RtcRtpTransceiverDirection, RtcRtpTransceiverInit, RtcTrackEvent,
+};
+
+/// What a track of the presenter is for. Viewers add one receiving
+/// transceiver per purpose in this order and the presenter sends each track
+/// on the transceiver of its purpose, so the position of a transceiver tells
+/// both sides what it carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrackPurpose {
+    Screen,
+    Camera,
+    SharedAudio,
+    Microphone,
+}
+
+pub const TRACK_PURPOSES: [TrackPurpose; 4] = [
+    TrackPurpose::Screen,
+    TrackPurpose::Camera,
+    TrackPurpose::SharedAudio,
+    TrackPurpose::Microphone,
+];
+
+impl TrackPurpose {
+    pub fn kind(self) -> &'static str {
+        match self {
+            TrackPurpose::Screen | TrackPurpose::Camera => "video",
+            TrackPurpose::SharedAudio | TrackPurpose::Microphone => "audio",
+        }
+    }
+}
+
+/// Adds the receiving transceivers of a viewer, before its first offer.
+pub fn add_receiving_transceivers(connection: &RtcPeerConnection) {
+    let mut init = RtcRtpTransceiverInit::new();
+    init.direction(RtcRtpTransceiverDirection::Recvonly);
+    for purpose in TRACK_PURPOSES.iter() {
+        connection.add_transceiver_with_str_and_init(purpose.kind(), &init);
+    }
+}
+
+/// The transceiver carrying tracks of the purpose, once negotiated.
+pub fn purpose_transceiver(
+    connection: &RtcPeerConnection,
+    purpose: TrackPurpose,
+) -> Option<RtcRtpTransceiver> {
+    let index = TRACK_PURPOSES.iter().position(|p| *p == purpose)?;
+    connection
+        .get_transceivers()
+        .get(index as u32)
+        .dyn_into::<RtcRtpTransceiver>()
+        .ok()
+}
+
+fn transceiver_purpose(
+    connection: &RtcPeerConnection,
+    transceiver: &RtcRtpTransceiver,
+) -> Option<TrackPurpose> {
+    let mid = transceiver.mid()?;
+    let index = connection.get_transceivers().iter().position(|other| {
+        other.unchecked_into::<RtcRtpTransceiver>().mid().as_ref() == Some(&mid)
+    })?;
+    TRACK_PURPOSES.get(index).copied()
+}
+
+#[derive(Default)]
+struct State {
+    tracks: HashMap<(UserId, TrackPurpose), MediaStreamTrack>,
+    /// Screen and audio of the presenter, shown by `external_video`.
+    main_stream: Option<MediaStream>,
+    /// Shown by `external_camera`.
+    camera_stream: Option<MediaStream>,
+}
+
+/// Tracks received from peers by purpose. Only the presenter sends, so
+/// whatever arrives is rendered into the external video elements.
+#[derive(Clone, Default)]
+pub struct RemoteMedia {
+    state: Rc<RefCell<State>>,
+}
+
+impl RemoteMedia {
+    pub fn setup(&self, connection: &RtcPeerConnection, peer_id: UserId) {
+        let remote_media = self.clone();
+        let connection_clone = connection.clone();
+        let on_track =
+            Closure::wrap(Box::new(move |event: RtcTrackEvent| {
+                match transceiver_purpose(&connection_clone, &event.transceiver()) {
+                    Some(purpose) => {
+                        remote_media.add_track(peer_id.clone(), purpose, event.track())
+                    }
+                    None => log(&format!("track of {:?} without purpose", peer_id)),
+                }
+            }) as Box<dyn FnMut(RtcTrackEvent)>);
+        connection.set_ontrack(Some(on_track.as_ref().unchecked_ref()));
+        on_track.forget();
+    }
+
+    pub fn clear(&self) {
+        *self.state.borrow_mut() = State::default();
+        if let Some(screen) = video_element("external_video") {
+            screen.set_src_object(None);
+        }
+        if let Some(camera) = video_element("external_camera") {
+            camera.set_src_object(None);
+            camera.set_hidden(true);
+        }
+        set_paused(false);
+    }
+
+    fn add_track(&self, peer_id: UserId, purpose: TrackPurpose, track: MediaStreamTrack) {
+        log(&format!("received {:?} track of {:?}", purpose, peer_id));
+        // A track stops being muted once the presenter sends on it again
+        let remote_media = self.clone();
+        let on_changed = Closure::wrap(Box::new(move || remote_media.render()) as Box<dyn FnMut()>);
+        track.set_onmute(Some(on_changed.as_ref().unchecked_ref()));
+        track.set_onunmute(Some(on_changed.as_ref().unchecked_ref()));
+        track.set_onended(Some(on_changed.as_ref().unchecked_ref()));
+        on_changed.forget();
+
+        {
+            let mut state = self.state.borrow_mut();
+            let stream = match purpose {
+                TrackPurpose::Camera => &mut state.camera_stream,
+                _ => &mut state.main_stream,
+            };
+            let stream = stream.get_or_insert_with(|| MediaStream::new().unwrap_throw());
+            if stream.get_track_by_id(&track.id()).is_none() {
+                stream.add_track(&track);
+            }
+            state.tracks.insert((peer_id, purpose), track);
+        }
+        self.render();
+    }
+
+    fn render(&self) {
+        let state = self.state.borrow();
+        let is_live = |purpose: TrackPurpose| {
+            state.tracks.iter().any(|((_, track_purpose), track)| {
+                *track_purpose == purpose
+                    && !track.muted()
+                    && track.ready_state() != MediaStreamTrackState::Ended
+            })
+        };
+        if let Some(screen) = video_element("external_video") {
+            if screen.src_object() != state.main_stream {
+                screen.set_src_object(state.main_stream.as_ref());
+            }
+        }
+        set_paused(state.main_stream.is_some() && !is_live(TrackPurpose::Screen));
+        if let Some(camera) = video_element("external_camera") {
+            if camera.src_object() != state.camera_stream {
+                camera.set_src_object(state.camera_stream.as_ref());
+            }
+            camera.set_hidden(!is_live(TrackPurpose::Camera));
+        }
+    }
+}
+
+/// Covers the last frame of a share that stopped, it would look frozen
+/// otherwise.
+fn set_paused(paused: bool) {
+    let overlay = web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|doc| doc.get_element_by_id("external_paused"))
+        .and_then(|element| element.dyn_into::<HtmlElement>().ok());
+    if let Some(overlay) = overlay {
+        overlay.set_hidden(!paused);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::ice::handle_ice_candidate;
+use crate::sdp::*;
+use crate::signalling::SignallingChannel;
+use crate::{log, log_error};
+use signalling_protocol::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use web_sys::{RtcPeerConnection, RtcSignalingState};
+
+#[derive(Default)]
+struct State {
+    /// This side's offer is being created.
+    making_offer: bool,
+    /// The peer's last offer collided with ours and was dropped.
+    ignore_offer: bool,
+    /// The answer to this side's offer is being applied.
+    setting_remote_answer: bool,
+}
+
+/// The W3C "perfect negotiation" pattern: both sides offer whenever the
+/// browser asks for a negotiation, the role assigned by the server decides
+/// which offer wins a collision.
+#[derive(Clone)]
+pub struct Negotiation {
+    connection: RtcPeerConnection,
+    session_id: SessionId,
+    peer_id: UserId,
+    signalling: SignallingChannel,
+    role: NegotiationRole,
+    state: Rc<RefCell<State>>,
+}
+
+impl Negotiation {
+    pub fn new(
+        connection: &RtcPeerConnection,
+        session_id: SessionId,
+        peer_id: UserId,
+        signalling: SignallingChannel,
+        role: NegotiationRole,
+    ) -> Self {
+        let negotiation = Self {
+            connection: connection.clone(),
+            session_id,
+            peer_id,
+            signalling,
+            role,
+            state: Rc::new(RefCell::new(State::default())),
+        };
+
+        let negotiation_clone = negotiation.clone();
+        let on_negotiation_needed = Closure::wrap(Box::new(move || {
+            negotiation_clone.offer_in_background(false);
+        }) as Box<dyn FnMut()>);
+        connection.set_onnegotiationneeded(Some(on_negotiation_needed.as_ref().unchecked_ref()));
+        on_negotiation_needed.forget();
+        negotiation
+    }
+
+    pub fn connection(&self) -> &RtcPeerConnection {
+        &self.connection
+    }
+
+    /// Offers new ICE credentials to recover a lost connection. A colliding
+    /// offer of the peer drops it, the caller tries again later.
+    pub fn restart_ice(&self) {
+        self.offer_in_background(true);
+    }
+
+    fn offer_in_background(&self, ice_restart: bool) {
+        let negotiation = self.clone();
+        spawn_local(async move {
+            if let Err(e) = negotiation.offer(ice_restart).await {
+                log_error(&format!(
+                    "error negotiating with {:?}: {:?}",
+                    negotiation.peer_id, e
+                ));
+            }
+        });
+    }
+
+    async fn offer(&self, ice_restart: bool) -> Result<(), JsValue> {
+        self.state.borrow_mut().making_offer = true;
+        let offer = create_sdp_offer(&self.connection, ice_restart).await;
+        self.state.borrow_mut().making_offer = false;
+        if let Some(offer) = offer? {
+            let msg = Signal::VideoOffer(self.session_id.clone(), self.peer_id.clone(), offer);
+            self.signalling.send(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the peer's offer unless it collides with this side's and this
+    /// side is impolite. Returns whether the offer needs an answer.
+    pub async fn handle_offer(&self, offer: &SessionDescription) -> Result<bool, JsValue> {
+        let collision = {
+            let state = self.state.borrow();
+            let stable = self.connection.signaling_state() == RtcSignalingState::Stable;
+            state.making_offer || !(stable || state.setting_remote_answer)
+        };
+        let ignore_offer = collision && self.role == NegotiationRole::Impolite;
+        self.state.borrow_mut().ignore_offer = ignore_offer;
+        if ignore_offer {
+            log("ignoring colliding offer");
+            return Ok(false);
+        }
+        // An offer still being created is dropped once it is ready
+        if collision && self.connection.signaling_state() == RtcSignalingState::HaveLocalOffer {
+            rollback_sdp_offer(&self.connection).await?;
+        }
+        handle_sdp_offer(&self.connection, offer).await?;
+        Ok(true)
+    }
+
+    pub async fn handle_answer(&self, answer: &SessionDescription) -> Result<(), JsValue> {
+        self.state.borrow_mut().setting_remote_answer = true;
+        let result = handle_sdp_answer(&self.connection, answer).await;
+        self.state.borrow_mut().setting_remote_answer = false;
+        result
+    }
+
+    /// Candidates belonging to an ignored offer are expected to fail.
+    pub async fn handle_candidate(&self, candidate: Option<&IceCandidate>) -> Result<(), JsValue> {
+        match handle_ice_candidate(&self.connection, candidate).await {
+            Err(_) if self.state.borrow().ignore_offer => Ok(()),
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::data_channel::DataChannels;
+use crate::log_error;
+use signalling_protocol::{RemoteControlMessage, RemoteInput, UserId};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use web_sys::RtcDataChannel;
+use yew::Callback;
+
+pub const CONTROL_CHANNEL_LABEL: &str = "control";
+
+const MAX_LOGGED_EVENTS: usize = 50;
+
+/// A viewer's control over the presenter's screen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ControlStatus {
+    #[default]
+    NotRequested,
+    Requested,
+    Granted,
+    Denied,
+}
+
+#[derive(Default)]
+struct State {
+    /// Viewer side.
+    status: ControlStatus,
+    /// Presenter side: viewers waiting for an answer.
+    requests: Vec<UserId>,
+    /// Presenter side: the viewer currently in control.
+    controller: Option<UserId>,
+    /// Presenter side: last normalized pointer position of the controller.
+    cursor: Option<(f64, f64)>,
+    /// Presenter side: received input, newest first.
+    events: VecDeque<String>,
+}
+
+/// Remote control over one data channel per peer. Input is only shown on the
+/// presenter's side, nothing is injected into the system.
+#[derive(Clone)]
+pub struct RemoteControl {
+    channels: DataChannels,
+    state: Rc<RefCell<State>>,
+    on_update: Callback<()>,
+}
+
+impl RemoteControl {
+    pub fn new(on_update: Callback<()>) -> Self {
+        Self {
+            channels: DataChannels::default(),
+            state: Rc::new(RefCell::new(State::default())),
+            on_update,
+        }
+    }
+
+    pub fn add_channel(&self, channel: &RtcDataChannel, peer_id: UserId) {
+        let remote_control = self.clone();
+        let on_message = move |peer_id: &UserId, data: JsValue| {
+            let message = data
+                .as_string()
+                .ok_or_else(|| "received non text control message".to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()));
+            match message {
+                Ok(message) => remote_control.handle_message(peer_id, message),
+                Err(e) => log_error(&format!("error handling control message: {}", e)),
+            }
+        };
+        let remote_control = self.clone();
+        let on_close = move |peer_id: &UserId| remote_control.channel_closed(peer_id);
+        self.channels.add(channel, peer_id, on_message, on_close);
+    }
+
+    /// Ends control by or over the peer.
+    pub fn remove_channel(&self, peer_id: &UserId) {
+        self.channels.remove(peer_id);
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        self.state.borrow().status
+    }
+
+    pub fn requests(&self) -> Vec<UserId> {
+        self.state.borrow().requests.clone()
+    }
+
+    pub fn controller(&self) -> Option<UserId> {
+        self.state.borrow().controller.clone()
+    }
+
+    pub fn cursor(&self) -> Option<(f64, f64)> {
+        self.state.borrow().cursor
+    }
+
+    pub fn events(&self) -> Vec<String> {
+        self.state.borrow().events.iter().cloned().collect()
+    }
+
+    /// Asks the presenter for control.
+    pub fn request(&self, presenter_id: &UserId) {
+        if self.send(presenter_id, &RemoteControlMessage::Request) {
+            self.state.borrow_mut().status = ControlStatus::Requested;
+            self.on_update.emit(());
+        }
+    }
+
+    /// Gives control to the viewer, taking it from the previous controller.
+    pub fn grant(&self, peer_id: &UserId) {
+        if let Some(controller) = self.controller() {
+            self.release(&controller);
+        }
+        self.state
+            .borrow_mut()
+            .requests
+            .retain(|user_id| user_id != peer_id);
+        if self.send(peer_id, &RemoteControlMessage::Grant) {
+            let mut state = self.state.borrow_mut();
+            state.controller = Some(peer_id.clone());
+            state.events.clear();
+        }
+        self.on_update.emit(());
+    }
+
+    pub fn deny(&self, peer_id: &UserId) {
+        self.state
+            .borrow_mut()
+            .requests
+            .retain(|user_id| user_id != peer_id);
+        self.send(peer_id, &RemoteControlMessage::Deny);
+        self.on_update.emit(());
+    }
+
+    /// Gives up control as a viewer or revokes it as the presenter.
+    pub fn release(&self, peer_id: &UserId) {
+        self.send(peer_id, &RemoteControlMessage::Release);
+        self.released(peer_id);
+    }
+
+    /// Input is dropped unless this viewer was granted control.
+    pub fn send_input(&self, presenter_id: &UserId, input: RemoteInput) {
+        if self.status() == ControlStatus::Granted {
+            self.send(presenter_id, &RemoteControlMessage::Input(input));
+        }
+    }
+
+    pub fn clear(&self) {
+        *self.state.borrow_mut() = State::default();
+        self.channels.clear();
+    }
+
+    fn send(&self, peer_id: &UserId, message: &RemoteControlMessage) -> bool {
+        let channel = match self.channels.get(peer_id) {
+            Some(channel) => channel,
+            None => {
+                log_error(&format!("no control channel to {:?}", peer_id));
+                return false;
+            }
+        };
+        let result = serde_json::to_string(message)
+            .map_err(|e| JsValue::from(e.to_string()))
+            .and_then(|text| channel.send_with_str(&text));
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                log_error(&format!("error sending control message: {:?}", e));
+                false
+            }
+        }
+    }
+
+    fn released(&self, peer_id: &UserId) {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.controller.as_ref() == Some(peer_id) {
+                state.controller = None;
+                state.cursor = None;
+            }
+            state.status = ControlStatus::NotRequested;
+        }
+        self.on_update.emit(());
+    }
+
+    fn channel_closed(&self, peer_id: &UserId) {
+        self.state
+            .borrow_mut()
+            .requests
+            .retain(|user_id| user_id != peer_id);
+        self.released(peer_id);
+    }
+
+    fn handle_message(&self, peer_id: &UserId, message: RemoteControlMessage) {
+        match message {
+            RemoteControlMessage::Request => {
+                let mut state = self.state.borrow_mut();
+                if !state.requests.contains(peer_id) {
+                    state.requests.push(peer_id.clone());
+                }
+            }
+            RemoteControlMessage::Grant => self.state.borrow_mut().status = ControlStatus::Granted,
+            RemoteControlMessage::Deny => self.state.borrow_mut().status = ControlStatus::Denied,
+            RemoteControlMessage::Release => return self.released(peer_id),
+            RemoteControlMessage::Input(input) => {
+                let mut state = self.state.borrow_mut();
+                if state.controller.as_ref() != Some(peer_id) {
+                    log_error(&format!("dropping input of {:?} not in control", peer_id));
+                    return;
+                }
+                match input {
+                    RemoteInput::PointerMove { x, y } => state.cursor = Some((x, y)),
+                    RemoteInput::PointerDown { x, y, .. }
+                    | RemoteInput::PointerUp { x, y, .. }
+                    | RemoteInput::Wheel { x, y, .. } => {
+                        state.cursor = Some((x, y));
+                        state.events.push_front(describe_input(&input));
+                    }
+                    RemoteInput::KeyDown { .. } | RemoteInput::KeyUp { .. } => {
+                        state.events.push_front(describe_input(&input));
+                    }
+                }
+                state.events.truncate(MAX_LOGGED_EVENTS);
+            }
+        }
+        self.on_update.emit(());
+    }
+}
+
+fn describe_input(input: &RemoteInput) -> String {
+    match input {
+        RemoteInput::PointerMove { x, y } => format!("move to ({:.3}, {:.3})", x, y),
+        RemoteInput::PointerDown { x, y, button } => {
+            format!("{:?} down at ({:.3}, {:.3})", button, x, y)
+        }
+        RemoteInput::PointerUp { x, y, button } => {
+            format!("{:?} up at ({:.3}, {:.3})", button, x, y)
+        }
+        RemoteInput::Wheel {
+            x,
+            y,
+            delta_x,
+            delta_y,
+        } => format!("wheel ({}, {}) at ({:.3}, {:.3})", delta_x, delta_y, x, y),
+        RemoteInput::KeyDown { key, modifiers, .. } => format!("key down {} {:?}", key, modifiers),
+        RemoteInput::KeyUp { key, modifiers, .. } => format!("key up {} {:?}", key, modifiers),
+    }
+}
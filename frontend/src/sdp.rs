@@ -1,58 +1,97 @@
 use crate::log;
 use js_sys::Reflect;
+use signalling_protocol::{SdpType, SessionDescription};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit};
+use web_sys::{
+    RtcOfferOptions, RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit, RtcSignalingState,
+};
 
-pub async fn create_sdp_offer(connection: &RtcPeerConnection) -> Result<String, JsValue> {
+fn rtc_sdp_type(sdp_type: SdpType) -> RtcSdpType {
+    match sdp_type {
+        SdpType::Offer => RtcSdpType::Offer,
+        SdpType::Pranswer => RtcSdpType::Pranswer,
+        SdpType::Answer => RtcSdpType::Answer,
+        SdpType::Rollback => RtcSdpType::Rollback,
+    }
+}
+
+fn rtc_session_description(description: &SessionDescription) -> RtcSessionDescriptionInit {
+    let mut description_obj = RtcSessionDescriptionInit::new(rtc_sdp_type(description.sdp_type));
+    description_obj.sdp(&description.sdp);
+    description_obj
+}
+
+/// Returns `None` if an offer of the peer was accepted while creating it.
+/// Restarting ICE makes both sides gather new candidates.
+pub async fn create_sdp_offer(
+    connection: &RtcPeerConnection,
+    ice_restart: bool,
+) -> Result<Option<SessionDescription>, JsValue> {
     log("create_sdp_offer");
-    let offer = JsFuture::from(connection.create_offer()).await?;
+    let mut options = RtcOfferOptions::new();
+    options.ice_restart(ice_restart);
+    let offer = JsFuture::from(connection.create_offer_with_rtc_offer_options(&options)).await?;
+    if connection.signaling_state() != RtcSignalingState::Stable {
+        log("dropping offer");
+        return Ok(None);
+    }
     let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))?
         .as_string()
         .unwrap();
-    let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-    offer_obj.sdp(&offer_sdp);
+    let offer = SessionDescription::new(SdpType::Offer, offer_sdp);
 
-    let sld_promise = connection.set_local_description(&offer_obj);
+    let sld_promise = connection.set_local_description(&rtc_session_description(&offer));
     JsFuture::from(sld_promise).await?;
 
     log("ok");
-    Ok(offer_sdp)
+    Ok(Some(offer))
+}
+
+/// Takes back the offer made to the peer.
+pub async fn rollback_sdp_offer(connection: &RtcPeerConnection) -> Result<(), JsValue> {
+    log("rollback_sdp_offer");
+    let rollback = SessionDescription::new(SdpType::Rollback, String::new());
+    let promise = connection.set_local_description(&rtc_session_description(&rollback));
+    JsFuture::from(promise).await?;
+    log("ok");
+    Ok(())
+}
+
+pub async fn handle_sdp_offer(
+    connection: &RtcPeerConnection,
+    offer: &SessionDescription,
+) -> Result<(), JsValue> {
+    log("handle_sdp_offer");
+    let promise = connection.set_remote_description(&rtc_session_description(offer));
+    JsFuture::from(promise).await?;
+    log("ok");
+    Ok(())
 }
 
 pub async fn create_sdp_answer(
     connection: &RtcPeerConnection,
-    offer: &str,
-) -> Result<String, JsValue> {
+) -> Result<SessionDescription, JsValue> {
     log("create_sdp_answer");
-    let mut offer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-    offer_obj.sdp(&offer);
-    let srd_promise = connection.set_remote_description(&offer_obj);
-    JsFuture::from(srd_promise).await?;
-
     let answer = JsFuture::from(connection.create_answer()).await?;
     let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))?
         .as_string()
         .unwrap();
+    let answer = SessionDescription::new(SdpType::Answer, answer_sdp);
 
-    let mut answer_obj = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-    answer_obj.sdp(&answer_sdp);
-
-    let sld_promise = connection.set_local_description(&answer_obj);
+    let sld_promise = connection.set_local_description(&rtc_session_description(&answer));
     JsFuture::from(sld_promise).await?;
 
     log("ok");
-    Ok(answer_sdp)
+    Ok(answer)
 }
 
 pub async fn handle_sdp_answer(
     connection: &RtcPeerConnection,
-    answer: &str,
+    answer: &SessionDescription,
 ) -> Result<(), JsValue> {
     log("handle_sdp_answer");
-    let mut rtc_answer = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-    rtc_answer.sdp(answer);
-    let promise = connection.set_remote_description(&rtc_answer);
+    let promise = connection.set_remote_description(&rtc_session_description(answer));
     JsFuture::from(promise).await?;
     log("ok");
     Ok(())
@@ -0,0 +1,77 @@
+use crate::log;
+use signalling_protocol::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use web_sys::WebSocket;
+
+#[derive(Default)]
+struct SignallingChannelState {
+    web_socket: Option<WebSocket>,
+    ready: bool,
+    pending: Vec<Signal>,
+}
+
+/// Connection to the signalling server that outlives the underlying web
+/// socket. Signals sent while the socket is down are queued and replayed once
+/// the server accepted the next connection.
+#[derive(Clone, Default)]
+pub struct SignallingChannel {
+    state: Rc<RefCell<SignallingChannelState>>,
+}
+
+impl SignallingChannel {
+    pub fn set_web_socket(&self, web_socket: WebSocket) {
+        let mut state = self.state.borrow_mut();
+        state.web_socket = Some(web_socket);
+        state.ready = false;
+    }
+
+    pub fn has_web_socket(&self) -> bool {
+        self.state.borrow().web_socket.is_some()
+    }
+
+    /// Sends the signal bypassing the queue. Only meant for the handshake.
+    pub fn send_now(&self, signal: &Signal) -> Result<(), JsValue> {
+        let message = match serde_json::to_string(signal) {
+            Ok(x) => x,
+            Err(e) => return Err(e.to_string().into()),
+        };
+        match self.state.borrow().web_socket.as_ref() {
+            Some(web_socket) => web_socket.send_with_str(&message),
+            None => Err("web socket not opened".into()),
+        }
+    }
+
+    pub fn send(&self, signal: Signal) -> Result<(), JsValue> {
+        let is_open = match self.state.borrow().web_socket.as_ref() {
+            Some(web_socket) => web_socket.ready_state() == WebSocket::OPEN,
+            None => return Err("web socket not opened".into()),
+        };
+        if is_open && self.state.borrow().ready {
+            self.send_now(&signal)
+        } else {
+            log(&format!("queueing signal until reconnected: {:?}", signal));
+            self.state.borrow_mut().pending.push(signal);
+            Ok(())
+        }
+    }
+
+    /// Marks the connection as accepted by the server and replays the queue.
+    pub fn set_ready(&self) -> Result<(), JsValue> {
+        self.state.borrow_mut().ready = true;
+        let pending = std::mem::take(&mut self.state.borrow_mut().pending);
+        for signal in pending.iter() {
+            self.send_now(signal)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_disconnected(&self) {
+        self.state.borrow_mut().ready = false;
+    }
+
+    pub fn clear_pending(&self) {
+        self.state.borrow_mut().pending.clear();
+    }
+}
@@ -0,0 +1,44 @@
+use wasm_bindgen::JsCast;
+use web_sys::{HtmlVideoElement, MouseEvent};
+
+pub fn video_element(id: &str) -> Option<HtmlVideoElement> {
+    web_sys::window()?
+        .document()?
+        .get_element_by_id(id)?
+        .dyn_into::<HtmlVideoElement>()
+        .ok()
+}
+
+/// Area of the element covered by the letterboxed video as left, top,
+/// width and height in CSS pixels. `None` while nothing is playing.
+pub fn video_content_box(video: &HtmlVideoElement) -> Option<(f64, f64, f64, f64)> {
+    let stream_width = video.video_width() as f64;
+    let stream_height = video.video_height() as f64;
+    if stream_width == 0.0 || stream_height == 0.0 {
+        return None;
+    }
+    let width = video.client_width() as f64;
+    let height = video.client_height() as f64;
+    let scale = (width / stream_width).min(height / stream_height);
+    let (content_width, content_height) = (stream_width * scale, stream_height * scale);
+    Some((
+        (width - content_width) / 2.0,
+        (height - content_height) / 2.0,
+        content_width,
+        content_height,
+    ))
+}
+
+/// Normalized position of the pointer on the stream shown by the video. The
+/// event has to target the video or an element covering exactly the video.
+/// `None` outside of the video content.
+pub fn stream_position(video: &HtmlVideoElement, event: &MouseEvent) -> Option<(f64, f64)> {
+    let (left, top, width, height) = video_content_box(video)?;
+    let x = (event.offset_x() as f64 - left) / width;
+    let y = (event.offset_y() as f64 - top) / height;
+    if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) {
+        Some((x, y))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,314 @@
+use log::LevelFilter;
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+
+const DEFAULT_ADDRESS: ([u8; 4], u16) = ([0, 0, 0, 0], 9999);
+const DEFAULT_CERT_PATH: &str = "cert.crt";
+const DEFAULT_KEY_PATH: &str = "key.rsa";
+const DEFAULT_RESUME_GRACE_SECS: u64 = 30;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 45;
+/// Flags are read from the environment by hand, clap turns flags with an
+/// environment variable into options taking a value.
+const PLAINTEXT_ENV: &str = "SCREEN_SHARE_PLAINTEXT";
+const BAN_ADDRESSES_ENV: &str = "SCREEN_SHARE_BAN_ADDRESSES";
+
+/// Command line arguments. Every option can also be set through the
+/// environment variable listed in `--help` and takes precedence over the
+/// configuration file.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "server", about = "Screen share signalling server")]
+struct Cli {
+    /// Path to an optional TOML configuration file
+    #[structopt(short, long, env = "SCREEN_SHARE_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:9999
+    #[structopt(short, long, env = "SCREEN_SHARE_ADDRESS")]
+    address: Option<SocketAddr>,
+
+    /// Path to the TLS certificate
+    #[structopt(long, env = "SCREEN_SHARE_CERT", parse(from_os_str))]
+    cert: Option<PathBuf>,
+
+    /// Path to the TLS private key
+    #[structopt(long, env = "SCREEN_SHARE_KEY", parse(from_os_str))]
+    key: Option<PathBuf>,
+
+    /// Serve without TLS, e.g. behind a TLS terminating reverse proxy. Also
+    /// set through SCREEN_SHARE_PLAINTEXT=true
+    #[structopt(long)]
+    plaintext: bool,
+
+    /// Ban the address of banned users as well, which bans everyone sharing
+    /// it. Also set through SCREEN_SHARE_BAN_ADDRESSES=true
+    #[structopt(long)]
+    ban_addresses: bool,
+
+    /// Header a trusted reverse proxy puts the client address in, e.g.
+    /// X-Forwarded-For
+    #[structopt(long, env = "SCREEN_SHARE_CLIENT_ADDRESS_HEADER")]
+    client_address_header: Option<String>,
+
+    /// Directory with the compiled frontend to serve next to the web socket
+    #[structopt(long, env = "SCREEN_SHARE_STATIC_DIR", parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug, trace
+    #[structopt(long, env = "SCREEN_SHARE_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+
+    /// Maximum number of concurrent sessions
+    #[structopt(long, env = "SCREEN_SHARE_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Maximum number of viewers in a single session
+    #[structopt(long, env = "SCREEN_SHARE_MAX_VIEWERS")]
+    max_viewers: Option<usize>,
+
+    /// Seconds a disconnected user can take to reconnect and resume
+    #[structopt(long, env = "SCREEN_SHARE_RESUME_GRACE_SECS")]
+    resume_grace_secs: Option<u64>,
+
+    /// Seconds between pings sent to every client
+    #[structopt(long, env = "SCREEN_SHARE_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+
+    /// Seconds of silence after which a client is disconnected
+    #[structopt(long, env = "SCREEN_SHARE_HEARTBEAT_TIMEOUT_SECS")]
+    heartbeat_timeout_secs: Option<u64>,
+}
+
+/// Layout of the configuration file. All keys are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    address: Option<SocketAddr>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    plaintext: Option<bool>,
+    ban_addresses: Option<bool>,
+    client_address_header: Option<String>,
+    static_dir: Option<PathBuf>,
+    log_level: Option<LevelFilter>,
+    max_sessions: Option<usize>,
+    max_viewers: Option<usize>,
+    resume_grace_secs: Option<u64>,
+    heartbeat_interval_secs: Option<u64>,
+    heartbeat_timeout_secs: Option<u64>,
+}
+
+impl FileConfig {
+    fn from_path(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("can not read config file {:?}: {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("can not parse config file {:?}: {}", path, e))
+    }
+}
+
+#[derive(Debug)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub address: SocketAddr,
+    /// `None` when running in plaintext mode.
+    pub tls: Option<TlsConfig>,
+    /// Frontend bundle served on the same listener, if any.
+    pub static_dir: Option<PathBuf>,
+    pub log_level: LevelFilter,
+    pub max_sessions: Option<usize>,
+    pub max_viewers: Option<usize>,
+    /// Whether bans cover the client address as well as the user.
+    pub ban_addresses: bool,
+    /// Header carrying the client address instead of the connection's
+    /// remote address.
+    pub client_address_header: Option<String>,
+    /// How long a disconnected user is kept around for resuming.
+    pub resume_grace_period: Duration,
+    pub heartbeat_interval: Duration,
+    /// Also bounds how long a new connection may take to send `Hello`.
+    pub heartbeat_timeout: Duration,
+}
+
+impl Config {
+    /// Builds the configuration from, in order of precedence, the command
+    /// line, the environment, the configuration file and the defaults.
+    pub fn load() -> Result<Self, String> {
+        Self::from_cli(Cli::from_args())
+    }
+
+    fn from_cli(cli: Cli) -> Result<Self, String> {
+        let file = match cli.config.as_ref() {
+            Some(path) => FileConfig::from_path(path)?,
+            None => FileConfig::default(),
+        };
+
+        // The flag can only turn plaintext mode on
+        let plaintext = cli.plaintext || flag(PLAINTEXT_ENV, file.plaintext)?;
+        let tls = if plaintext {
+            None
+        } else {
+            Some(TlsConfig {
+                cert: cli
+                    .cert
+                    .or(file.cert)
+                    .unwrap_or_else(|| DEFAULT_CERT_PATH.into()),
+                key: cli
+                    .key
+                    .or(file.key)
+                    .unwrap_or_else(|| DEFAULT_KEY_PATH.into()),
+            })
+        };
+
+        let config = Self {
+            address: cli
+                .address
+                .or(file.address)
+                .unwrap_or_else(|| DEFAULT_ADDRESS.into()),
+            tls,
+            static_dir: cli.static_dir.or(file.static_dir),
+            log_level: cli
+                .log_level
+                .or(file.log_level)
+                .unwrap_or(LevelFilter::Info),
+            max_sessions: cli.max_sessions.or(file.max_sessions),
+            max_viewers: cli.max_viewers.or(file.max_viewers),
+            ban_addresses: cli.ban_addresses || flag(BAN_ADDRESSES_ENV, file.ban_addresses)?,
+            client_address_header: cli.client_address_header.or(file.client_address_header),
+            resume_grace_period: Duration::from_secs(
+                cli.resume_grace_secs
+                    .or(file.resume_grace_secs)
+                    .unwrap_or(DEFAULT_RESUME_GRACE_SECS),
+            ),
+            heartbeat_interval: Duration::from_secs(
+                cli.heartbeat_interval_secs
+                    .or(file.heartbeat_interval_secs)
+                    .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            ),
+            heartbeat_timeout: Duration::from_secs(
+                cli.heartbeat_timeout_secs
+                    .or(file.heartbeat_timeout_secs)
+                    .unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT_SECS),
+            ),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_interval.is_zero() {
+            return Err("heartbeat_interval_secs must be at least 1".to_string());
+        }
+        if self.heartbeat_timeout < self.heartbeat_interval {
+            return Err(format!(
+                "heartbeat_timeout_secs ({}) must not be shorter than heartbeat_interval_secs ({})",
+                self.heartbeat_timeout.as_secs(),
+                self.heartbeat_interval.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Value of a flag not given on the command line, from the environment or
+/// the configuration file.
+fn flag(env: &str, file: Option<bool>) -> Result<bool, String> {
+    let value = match std::env::var(env) {
+        Ok(value) => parse_bool(&value)
+            .ok_or_else(|| format!("{} must be true or false, got {:?}", env, value))?,
+        Err(std::env::VarError::NotPresent) => file.unwrap_or(false),
+        Err(e) => return Err(format!("can not read {}: {}", env, e)),
+    };
+    Ok(value)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The environment is shared by all tests.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Config, String> {
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+        let cli = Cli::from_iter_safe(std::iter::once("server").chain(args.iter().cloned()))
+            .map_err(|e| e.to_string());
+        let config = cli.and_then(Config::from_cli);
+        for (key, _) in env {
+            std::env::remove_var(key);
+        }
+        config
+    }
+
+    fn config_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("screen-share-{}.toml", name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn cli_over_env_over_file_over_default() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let file = config_file("precedence", "max_viewers = 3\nmax_sessions = 7\n");
+        let env = [("SCREEN_SHARE_MAX_VIEWERS", "4")];
+
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.max_viewers, None);
+        assert_eq!(config.address, DEFAULT_ADDRESS.into());
+        let config = load(&["--config", &file], &[]).unwrap();
+        assert_eq!(config.max_viewers, Some(3));
+        assert_eq!(config.max_sessions, Some(7));
+        let config = load(&["--config", &file], &env).unwrap();
+        assert_eq!(config.max_viewers, Some(4));
+        assert_eq!(config.max_sessions, Some(7));
+        let config = load(&["--config", &file, "--max-viewers", "5"], &env).unwrap();
+        assert_eq!(config.max_viewers, Some(5));
+    }
+
+    #[test]
+    fn plaintext_from_flag_env_or_file() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let file = config_file("plaintext", "plaintext = true\n");
+        let env_off = [(PLAINTEXT_ENV, "false")];
+
+        assert!(load(&[], &[]).unwrap().tls.is_some());
+        assert!(load(&["--config", &file], &[]).unwrap().tls.is_none());
+        assert!(load(&["--config", &file], &env_off).unwrap().tls.is_some());
+        assert!(load(&["--plaintext"], &env_off).unwrap().tls.is_none());
+        assert!(load(&[], &[(PLAINTEXT_ENV, "yes")]).unwrap().tls.is_none());
+        assert!(load(&[], &[(PLAINTEXT_ENV, "maybe")]).is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_heartbeat() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        assert!(load(&["--heartbeat-interval-secs", "0"], &[]).is_err());
+        let args = [
+            "--heartbeat-interval-secs",
+            "10",
+            "--heartbeat-timeout-secs",
+            "5",
+        ];
+        assert!(load(&args, &[]).is_err());
+    }
+}
@@ -1,158 +1,789 @@
-use futures::{FutureExt, StreamExt};
-use std::{collections::HashMap, convert::Infallible, result::Result, sync::Arc};
+use futures::{stream::SplitStream, FutureExt, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    result::Result,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::{mpsc, Mutex};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 use warp::{
+    http::HeaderMap,
     ws::{Message, WebSocket},
     Filter, Rejection, Reply,
 };
 
 use signalling_protocol::*;
 
+mod config;
+use config::*;
+
+mod session_code;
+use session_code::*;
+
 pub struct User {
     sender: mpsc::UnboundedSender<Result<Message, warp::Error>>,
     session_id: Option<SessionId>,
     user_id: UserId,
+    /// Name given when joining the current session.
+    display_name: String,
+    resume_token: ResumeToken,
+    /// Identifies the web socket currently serving the user.
+    connection_id: Uuid,
+    /// Client address of that web socket, only known if bans cover it.
+    address: Option<IpAddr>,
+    /// Joins turned away since the last one that went through. Each makes
+    /// the next join wait longer, so that session codes and passwords can
+    /// not be guessed quickly.
+    failed_joins: u32,
+    disconnected: bool,
 }
 
 #[derive(Debug)]
 pub struct Session {
     host: UserId,
-    guest: Option<UserId>,
+    /// Every participant, the host included, with the part it plays.
+    roles: HashMap<UserId, Role>,
+    password: Option<String>,
+    waiting_room: bool,
+    /// Joiners waiting for a moderator's approval.
+    pending: HashSet<UserId>,
+    banned: HashSet<UserId>,
+    /// Addresses of banned users, so that reconnecting with a new `UserId`
+    /// does not lift a ban.
+    banned_addresses: HashSet<IpAddr>,
+}
+
+impl Session {
+    fn check_password(&self, password: Option<&String>) -> bool {
+        match self.password.as_ref() {
+            Some(expected) => password == Some(expected),
+            None => true,
+        }
+    }
+
+    /// The host does not count as a viewer.
+    fn is_full(&self, max_viewers: Option<usize>) -> bool {
+        match max_viewers {
+            Some(max_viewers) => self.roles.len() > max_viewers,
+            None => false,
+        }
+    }
+
+    fn is_banned(&self, user: &User) -> bool {
+        self.banned.contains(&user.user_id)
+            || user
+                .address
+                .is_some_and(|address| self.banned_addresses.contains(&address))
+    }
+
+    /// Loopback addresses are shared by everyone behind a local reverse
+    /// proxy, so only the `UserId` is banned for them.
+    fn ban(&mut self, user: &User) {
+        self.banned.insert(user.user_id.clone());
+        if let Some(address) = user.address.filter(|address| !address.is_loopback()) {
+            self.banned_addresses.insert(address);
+        }
+    }
+
+    /// Turns away banned users, wrong passwords and joiners of a full
+    /// session.
+    fn check_join(
+        &self,
+        session_id: &SessionId,
+        user: &User,
+        password: Option<&String>,
+        max_viewers: Option<usize>,
+    ) -> Result<(), SignalError> {
+        if self.is_banned(user) {
+            return Err(SignalError::Banned(session_id.clone()));
+        }
+        if !self.check_password(password) {
+            warn!("user {:?} gave a wrong session password", user.user_id);
+            return Err(SignalError::AccessDenied(session_id.clone()));
+        }
+        if self.is_full(max_viewers) {
+            return Err(SignalError::SessionFull(session_id.clone()));
+        }
+        Ok(())
+    }
+
+    fn is_participant(&self, user_id: &UserId) -> bool {
+        self.roles.contains_key(user_id)
+    }
+
+    fn can_moderate(&self, user_id: &UserId) -> bool {
+        *user_id == self.host || self.roles.get(user_id) == Some(&Role::CoHost)
+    }
+
+    fn presenter(&self) -> Option<&UserId> {
+        self.roles
+            .iter()
+            .find(|(_, role)| **role == Role::Presenter)
+            .map(|(user_id, _)| user_id)
+    }
+
+    /// Media signalling is only relayed between the presenter and everyone
+    /// else, chat between any two participants.
+    fn can_relay(&self, from: &UserId, to: &UserId, signal: &Signal) -> bool {
+        if from == to || !self.is_participant(from) || !self.is_participant(to) {
+            return false;
+        }
+        match signal {
+            Signal::Chat(..) => true,
+            _ => {
+                let presenter = self.presenter();
+                presenter == Some(from) || presenter == Some(to)
+            }
+        }
+    }
 }
 
 type Users = Arc<Mutex<HashMap<UserId, User>>>;
 type Sessions = Arc<Mutex<HashMap<SessionId, Session>>>;
+type SharedConfig = Arc<Config>;
+
+/// Wait before another join per join that failed, capped at the heartbeat
+/// interval since no heartbeats are read meanwhile.
+const FAILED_JOIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Oldest protocol version the server still talks to.
+const MIN_PROTOCOL_VERSION: u32 = 9;
+const SERVER_CAPABILITIES: &[Capability] = &[
+    Capability::MultipleViewers,
+    Capability::EndOfCandidates,
+    Capability::Resume,
+];
 
-pub async fn send_signal(user: &User, signal: Signal) -> Result<(), String> {
-    println!("Sending to user: {:#?} signal: {:#?}", user.user_id, signal);
+pub async fn send_signal(user: &User, signal: Signal) -> Result<(), SignalError> {
+    debug!("Sending to user: {:#?} signal: {:#?}", user.user_id, signal);
     let message = match serde_json::to_string(&signal) {
         Ok(msg) => msg,
-        Err(_) => return Err(format!("can not serialize signal: {:?}", signal)),
+        Err(_) => {
+            return Err(SignalError::Internal(format!(
+                "can not serialize signal: {:?}",
+                signal
+            )))
+        }
     };
 
     match user.sender.send(Ok(Message::text(message))) {
         Ok(()) => Ok(()),
-        Err(e) => Err(e.to_string()),
+        Err(_) => Err(SignalError::PeerUnavailable(user.user_id.clone())),
     }
 }
 
+pub async fn relay_signal(
+    user_id: &UserId,
+    session_id: &SessionId,
+    peer_id: &UserId,
+    signal: Signal,
+    users: Users,
+    sessions: Sessions,
+) -> Result<(), SignalError> {
+    match sessions.lock().await.get(session_id) {
+        Some(session) if !session.is_participant(user_id) => {
+            Err(SignalError::NotParticipant(session_id.clone()))
+        }
+        Some(session) if session.can_relay(user_id, peer_id, &signal) => {
+            match users.lock().await.get(peer_id) {
+                Some(peer) => send_signal(peer, signal).await,
+                None => Err(SignalError::PeerUnavailable(peer_id.clone())),
+            }
+        }
+        Some(_) => Err(SignalError::PeerUnavailable(peer_id.clone())),
+        None => Err(SignalError::UnknownSession(session_id.clone())),
+    }
+}
+
+/// Sends the signal to every participant of the session.
+async fn broadcast_signal(session: &Session, users: &HashMap<UserId, User>, signal: Signal) {
+    for participant_id in session.roles.keys() {
+        if let Some(participant) = users.get(participant_id) {
+            if let Err(e) = send_signal(participant, signal.clone()).await {
+                warn!("error notifying participant {:?}: {:?}", participant_id, e);
+            }
+        }
+    }
+}
+
+/// Removes the user from its current session. If the user was hosting, the
+/// whole session is dropped and everyone else is told it has ended, otherwise
+/// the remaining participants are told that the peer has left.
+pub async fn leave_session(user_id: &UserId, users: Users, sessions: Sessions) {
+    let mut sessions = sessions.lock().await;
+    let mut users = users.lock().await;
+    leave_session_locked(user_id, &mut users, &mut sessions).await;
+}
+
+async fn leave_session_locked(
+    user_id: &UserId,
+    users: &mut HashMap<UserId, User>,
+    sessions: &mut HashMap<SessionId, Session>,
+) {
+    let session_id = match users
+        .get_mut(user_id)
+        .and_then(|user| user.session_id.take())
+    {
+        Some(session_id) => session_id,
+        None => return,
+    };
+
+    let is_host = match sessions.get(&session_id) {
+        Some(session) => session.host == *user_id,
+        None => return,
+    };
+
+    if is_host {
+        if let Some(session) = sessions.remove(&session_id) {
+            info!("Removed session: {:?}", session_id);
+            let members = session.roles.keys().chain(session.pending.iter());
+            for member_id in members.filter(|member_id| *member_id != user_id) {
+                if let Some(member) = users.get_mut(member_id) {
+                    member.session_id = None;
+                    let sig_msg = Signal::SessionEnded(session_id.clone());
+                    if let Err(e) = send_signal(member, sig_msg).await {
+                        warn!("error notifying member {:?}: {:?}", member_id, e);
+                    }
+                }
+            }
+        }
+    } else if let Some(session) = sessions.get_mut(&session_id) {
+        remove_member(&session_id, session, user_id, users).await;
+    }
+}
+
+/// Forgets a participant or a joiner in the waiting room and lets everyone
+/// else know. The host takes over if the participant was presenting.
+async fn remove_member(
+    session_id: &SessionId,
+    session: &mut Session,
+    user_id: &UserId,
+    users: &HashMap<UserId, User>,
+) {
+    session.pending.remove(user_id);
+    let role = session.roles.remove(user_id);
+    let sig_msg = Signal::PeerLeft(session_id.clone(), user_id.clone());
+    broadcast_signal(session, users, sig_msg).await;
+
+    if role == Some(Role::Presenter) {
+        let host = session.host.clone();
+        session.roles.insert(host.clone(), Role::Presenter);
+        let sig_msg = Signal::RoleChanged(session_id.clone(), host, Role::Presenter);
+        broadcast_signal(session, users, sig_msg).await;
+    }
+}
+
+/// Adds the user to the session as a viewer, introduces it to everyone else
+/// and tells it about the participants already there. The joiner is the
+/// polite side of every negotiation with them.
+async fn admit_participant(
+    session_id: &SessionId,
+    session: &mut Session,
+    user_id: &UserId,
+    users: &mut HashMap<UserId, User>,
+) -> Result<(), SignalError> {
+    let display_name = match users.get_mut(user_id) {
+        Some(user) => {
+            user.session_id = Some(session_id.clone());
+            let sig_msg = Signal::SessionJoinSuccess(session_id.clone(), session.host.clone());
+            send_signal(user, sig_msg).await?;
+            user.display_name.clone()
+        }
+        None => return Err(SignalError::UnknownUser(user_id.clone())),
+    };
+    let sig_msg = Signal::PeerJoined(
+        session_id.clone(),
+        user_id.clone(),
+        display_name,
+        NegotiationRole::Impolite,
+    );
+    broadcast_signal(session, users, sig_msg).await;
+    session.roles.insert(user_id.clone(), Role::Viewer);
+
+    let user = match users.get(user_id) {
+        Some(user) => user,
+        None => return Err(SignalError::UnknownUser(user_id.clone())),
+    };
+    for (participant_id, role) in session.roles.iter() {
+        if participant_id == user_id {
+            continue;
+        }
+        let display_name = match users.get(participant_id) {
+            Some(participant) => participant.display_name.clone(),
+            None => continue,
+        };
+        let sig_msg = Signal::PeerJoined(
+            session_id.clone(),
+            participant_id.clone(),
+            display_name,
+            NegotiationRole::Polite,
+        );
+        send_signal(user, sig_msg).await?;
+        let sig_msg = Signal::RoleChanged(session_id.clone(), participant_id.clone(), *role);
+        send_signal(user, sig_msg).await?;
+    }
+    Ok(())
+}
+
+/// Drops a participant or a joiner in the waiting room from the session and
+/// tells it why.
+async fn remove_participant(
+    session_id: &SessionId,
+    session: &mut Session,
+    peer_id: &UserId,
+    users: &mut HashMap<UserId, User>,
+    reason: SignalError,
+) -> Result<(), SignalError> {
+    if *peer_id == session.host {
+        return Err(SignalError::PermissionDenied);
+    }
+    if !session.is_participant(peer_id) && !session.pending.contains(peer_id) {
+        return Err(SignalError::PeerUnavailable(peer_id.clone()));
+    }
+    remove_member(session_id, session, peer_id, users).await;
+    info!(
+        "Removed {:?} from session {:?}: {}",
+        peer_id, session_id, reason
+    );
+    match users.get_mut(peer_id) {
+        Some(peer) => {
+            peer.session_id = None;
+            send_signal(peer, Signal::Error(reason)).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns the session the user takes part in.
+fn participant_session<'a>(
+    user_id: &UserId,
+    users: &HashMap<UserId, User>,
+    sessions: &'a mut HashMap<SessionId, Session>,
+) -> Result<(SessionId, &'a mut Session), SignalError> {
+    let session_id = match users.get(user_id) {
+        Some(user) => user.session_id.clone().ok_or(SignalError::NotInSession)?,
+        None => return Err(SignalError::UnknownUser(user_id.clone())),
+    };
+    match sessions.get_mut(&session_id) {
+        Some(session) if session.is_participant(user_id) => Ok((session_id, session)),
+        Some(_) => Err(SignalError::NotParticipant(session_id)),
+        None => Err(SignalError::UnknownSession(session_id)),
+    }
+}
+
+/// Returns the session the user can moderate.
+fn moderated_session<'a>(
+    user_id: &UserId,
+    users: &HashMap<UserId, User>,
+    sessions: &'a mut HashMap<SessionId, Session>,
+) -> Result<(SessionId, &'a mut Session), SignalError> {
+    let (session_id, session) = participant_session(user_id, users, sessions)?;
+    if !session.can_moderate(user_id) {
+        return Err(SignalError::PermissionDenied);
+    }
+    Ok((session_id, session))
+}
+
 pub async fn handle_message(
     user_id: &UserId,
     msg: &Message,
     users: Users,
     sessions: Sessions,
-) -> Result<(), String> {
+    config: SharedConfig,
+) -> Result<(), SignalError> {
     let msg = match msg.to_str() {
         Ok(m) => m,
         Err(_) => {
-            return Err("message is not a str".to_string());
+            return Err(SignalError::MalformedPayload(
+                "message is not a str".to_string(),
+            ));
         }
     };
 
     let result: Signal = match serde_json::from_str(&msg) {
         Ok(x) => x,
         Err(e) => {
-            return Err(e.to_string());
+            return Err(SignalError::MalformedPayload(e.to_string()));
         }
     };
 
-    println!("Handling signal: {:#?}", result);
+    debug!("Handling signal: {:#?}", result);
 
     match result {
-        Signal::SessionNew => {
-            let new_session_id = SessionId::new(Uuid::new_v4().to_string());
+        Signal::SessionNew(options) => {
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            if let Some(max_sessions) = config.max_sessions {
+                // The session the user hosts ends once it creates a new one
+                let hosted = users
+                    .get(user_id)
+                    .and_then(|user| user.session_id.as_ref())
+                    .and_then(|session_id| sessions.get(session_id))
+                    .filter(|session| session.host == *user_id);
+                if sessions.len() - hosted.iter().count() >= max_sessions {
+                    return Err(SignalError::SessionLimitReached);
+                }
+            }
+            leave_session_locked(user_id, &mut users, &mut sessions).await;
+
+            let new_session_id = new_session_code(&sessions);
+            let mut roles = HashMap::new();
+            roles.insert(user_id.clone(), Role::Presenter);
             let new_session = Session {
                 host: user_id.clone(),
-                guest: None,
+                roles,
+                password: options.password.filter(|password| !password.is_empty()),
+                waiting_room: options.waiting_room,
+                pending: HashSet::new(),
+                banned: HashSet::new(),
+                banned_addresses: HashSet::new(),
             };
 
-            println!("Created new session: {:?}", new_session_id);
+            info!("Created new session: {:?}", new_session_id);
 
-            sessions
-                .lock()
-                .await
-                .insert(new_session_id.clone(), new_session);
+            sessions.insert(new_session_id.clone(), new_session);
 
-            match users.lock().await.get_mut(user_id) {
+            match users.get_mut(user_id) {
                 Some(user) => {
                     user.session_id = Some(new_session_id.clone());
+                    user.display_name = options.display_name;
                     let sig_msg = Signal::SessionCreated(new_session_id);
                     send_signal(&user, sig_msg).await?;
                 }
-                None => return Err(format!("can not find user {:?}", user_id)),
+                None => return Err(SignalError::UnknownUser(user_id.clone())),
             }
         }
-        Signal::SessionJoin(session_id) => match sessions.lock().await.get_mut(&session_id) {
-            Some(session) => {
-                session.guest = Some(user_id.clone());
-                match users.lock().await.get_mut(user_id) {
-                    Some(user) => {
-                        let sig_msg = Signal::SessionJoinSuccess(session_id);
-                        send_signal(&user, sig_msg).await?;
-                    }
-                    None => return Err(format!("can not find user {:?}", user_id)),
+        Signal::SessionJoin(session_id, options) => {
+            let failed_joins = match users.lock().await.get(user_id) {
+                Some(user) => user.failed_joins,
+                None => return Err(SignalError::UnknownUser(user_id.clone())),
+            };
+            if failed_joins > 0 {
+                let delay = FAILED_JOIN_DELAY * failed_joins;
+                tokio::time::sleep(delay.min(config.heartbeat_interval)).await;
+            }
+
+            let session_id = normalize_session_code(&session_id);
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            let user = match users.get_mut(user_id) {
+                Some(user) => user,
+                None => return Err(SignalError::UnknownUser(user_id.clone())),
+            };
+            // The current session is only left once the join goes through
+            let password = options.password.as_ref();
+            let checked = sessions
+                .get(&session_id)
+                .map(|session| session.check_join(&session_id, user, password, config.max_viewers));
+            match checked {
+                Some(Ok(())) => user.failed_joins = 0,
+                Some(Err(e)) => {
+                    user.failed_joins += 1;
+                    return Err(e);
+                }
+                None => {
+                    user.failed_joins += 1;
+                    return send_signal(user, Signal::SessionJoinError(session_id)).await;
                 }
             }
-            None => match users.lock().await.get(user_id) {
+            leave_session_locked(user_id, &mut users, &mut sessions).await;
+            // Leaving ends the session if the user was hosting it
+            let session = match sessions.get_mut(&session_id) {
+                Some(session) => session,
+                None => {
+                    return match users.get(user_id) {
+                        Some(user) => {
+                            let sig_msg = Signal::SessionJoinError(session_id);
+                            send_signal(user, sig_msg).await
+                        }
+                        None => Err(SignalError::UnknownUser(user_id.clone())),
+                    };
+                }
+            };
+            match users.get_mut(user_id) {
+                Some(user) => user.display_name = options.display_name.clone(),
+                None => return Err(SignalError::UnknownUser(user_id.clone())),
+            }
+            if !session.waiting_room {
+                return admit_participant(&session_id, session, user_id, &mut users).await;
+            }
+
+            match users.get_mut(user_id) {
                 Some(user) => {
-                    let sig_msg = Signal::SessionJoinError(session_id);
-                    send_signal(&user, sig_msg).await?;
+                    user.session_id = Some(session_id.clone());
+                    let sig_msg = Signal::SessionJoinPending(session_id.clone());
+                    send_signal(user, sig_msg).await?;
                 }
-                None => return Err(format!("can not find user {:?}", user_id)),
-            },
-        },
-        Signal::VideoOffer(session_id, offer) => match sessions.lock().await.get(&session_id) {
-            Some(session) => match users.lock().await.get(&session.host) {
-                Some(host) => {
-                    let sig_msg = Signal::VideoOffer(session_id, offer);
-                    send_signal(&host, sig_msg).await?;
+                None => return Err(SignalError::UnknownUser(user_id.clone())),
+            }
+            session.pending.insert(user_id.clone());
+            let sig_msg = Signal::JoinRequest(user_id.clone(), options.display_name);
+            for moderator_id in session.roles.keys() {
+                if !session.can_moderate(moderator_id) {
+                    continue;
                 }
-                None => return Err(format!("can not find user {:?}", user_id)),
-            },
-            None => return Err(format!("can not find session {:?}", session_id)),
-        },
-        Signal::VideoAnswer(session_id, answer) => match sessions.lock().await.get(&session_id) {
-            Some(session) => match users.lock().await.get(session.guest.as_ref().unwrap()) {
-                Some(guest) => {
-                    let sig_msg = Signal::VideoAnswer(session_id, answer);
-                    send_signal(&guest, sig_msg).await?;
+                if let Some(moderator) = users.get(moderator_id) {
+                    send_signal(moderator, sig_msg.clone()).await?;
                 }
-                None => return Err(format!("can not find user {:?}", user_id)),
-            },
-            None => return Err(format!("can not find session {:?}", session_id)),
-        },
-        Signal::ICECandidate(session_id, candidate) => match sessions.lock().await.get(&session_id)
-        {
-            Some(session) => {
-                println!(
-                    "Got ICECandidate: user_id: {:#?}, session: {:#?}",
-                    user_id, session
-                );
-                let destination = if *user_id == session.host {
-                    session.guest.as_ref().unwrap().clone()
+            }
+        }
+        Signal::JoinAccept(peer_id) => {
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            let (session_id, session) = moderated_session(user_id, &users, &mut sessions)?;
+            if session.is_full(config.max_viewers) {
+                return Err(SignalError::SessionFull(session_id));
+            }
+            if !session.pending.remove(&peer_id) {
+                return Err(SignalError::PeerUnavailable(peer_id));
+            }
+            admit_participant(&session_id, session, &peer_id, &mut users).await?;
+        }
+        Signal::JoinDeny(peer_id) => {
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            let (session_id, session) = moderated_session(user_id, &users, &mut sessions)?;
+            if !session.pending.contains(&peer_id) {
+                return Err(SignalError::PeerUnavailable(peer_id));
+            }
+            let reason = SignalError::JoinDenied(session_id.clone());
+            remove_participant(&session_id, session, &peer_id, &mut users, reason).await?;
+        }
+        Signal::Kick(peer_id) => {
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            let (session_id, session) = moderated_session(user_id, &users, &mut sessions)?;
+            let reason = SignalError::Kicked(session_id.clone());
+            remove_participant(&session_id, session, &peer_id, &mut users, reason).await?;
+        }
+        Signal::Ban(peer_id) => {
+            let mut sessions = sessions.lock().await;
+            let mut users = users.lock().await;
+            let (session_id, session) = moderated_session(user_id, &users, &mut sessions)?;
+            let reason = SignalError::Banned(session_id.clone());
+            remove_participant(&session_id, session, &peer_id, &mut users, reason).await?;
+            if let Some(peer) = users.get(&peer_id) {
+                session.ban(peer);
+            }
+        }
+        Signal::SetPresenter(peer_id) => {
+            let mut sessions = sessions.lock().await;
+            let users = users.lock().await;
+            let (session_id, session) = participant_session(user_id, &users, &mut sessions)?;
+            let previous = session.presenter().cloned();
+            if !session.can_moderate(user_id) && previous.as_ref() != Some(user_id) {
+                return Err(SignalError::PermissionDenied);
+            }
+            if !session.is_participant(&peer_id) {
+                return Err(SignalError::PeerUnavailable(peer_id));
+            }
+            if previous.as_ref() == Some(&peer_id) {
+                return Ok(());
+            }
+            // The host keeps moderating after presenting
+            if let Some(previous) = previous {
+                let role = if previous == session.host {
+                    Role::CoHost
                 } else {
-                    session.host.clone()
+                    Role::Viewer
                 };
-                match users.lock().await.get(&destination) {
-                    Some(user) => {
-                        let sig_msg = Signal::ICECandidate(session_id, candidate);
-                        send_signal(&user, sig_msg).await?;
-                    }
-                    None => return Err(format!("can not find user {:?}", user_id)),
+                session.roles.insert(previous.clone(), role);
+                let sig_msg = Signal::RoleChanged(session_id.clone(), previous, role);
+                broadcast_signal(session, &users, sig_msg).await;
+            }
+            session.roles.insert(peer_id.clone(), Role::Presenter);
+            info!("{:?} is presenting in session {:?}", peer_id, session_id);
+            let sig_msg = Signal::RoleChanged(session_id, peer_id, Role::Presenter);
+            broadcast_signal(session, &users, sig_msg).await;
+        }
+        Signal::SetCoHost(peer_id, co_host) => {
+            let mut sessions = sessions.lock().await;
+            let users = users.lock().await;
+            let (session_id, session) = participant_session(user_id, &users, &mut sessions)?;
+            if session.host != *user_id || peer_id == session.host {
+                return Err(SignalError::PermissionDenied);
+            }
+            let role = match session.roles.get_mut(&peer_id) {
+                // Roles are exclusive, the presenter has to hand over first
+                Some(Role::Presenter) => return Err(SignalError::PermissionDenied),
+                Some(role) => {
+                    *role = if co_host { Role::CoHost } else { Role::Viewer };
+                    *role
                 }
+                None => return Err(SignalError::PeerUnavailable(peer_id)),
+            };
+            let sig_msg = Signal::RoleChanged(session_id, peer_id, role);
+            broadcast_signal(session, &users, sig_msg).await;
+        }
+        Signal::SessionLeave(_) => leave_session(user_id, users, sessions).await,
+        Signal::VideoOffer(session_id, peer_id, offer) => {
+            offer
+                .validate(SdpType::Offer)
+                .map_err(SignalError::MalformedPayload)?;
+            let sig_msg = Signal::VideoOffer(session_id.clone(), user_id.clone(), offer);
+            relay_signal(user_id, &session_id, &peer_id, sig_msg, users, sessions).await?;
+        }
+        Signal::VideoAnswer(session_id, peer_id, answer) => {
+            answer
+                .validate(SdpType::Answer)
+                .map_err(SignalError::MalformedPayload)?;
+            let sig_msg = Signal::VideoAnswer(session_id.clone(), user_id.clone(), answer);
+            relay_signal(user_id, &session_id, &peer_id, sig_msg, users, sessions).await?;
+        }
+        Signal::ICECandidate(session_id, peer_id, candidate) => {
+            if let Some(candidate) = candidate.as_ref() {
+                candidate
+                    .validate()
+                    .map_err(SignalError::MalformedPayload)?;
             }
-            None => return Err(format!("can not find session {:?}", session_id)),
-        },
-        _ => {}
+            let sig_msg = Signal::ICECandidate(session_id.clone(), user_id.clone(), candidate);
+            relay_signal(user_id, &session_id, &peer_id, sig_msg, users, sessions).await?;
+        }
+        Signal::Chat(session_id, peer_id, text) => {
+            if text.chars().count() > MAX_CHAT_MESSAGE_LEN {
+                return Err(SignalError::MalformedPayload(format!(
+                    "chat message longer than {} characters",
+                    MAX_CHAT_MESSAGE_LEN
+                )));
+            }
+            let sig_msg = Signal::Chat(session_id.clone(), user_id.clone(), text);
+            relay_signal(user_id, &session_id, &peer_id, sig_msg, users, sessions).await?;
+        }
+        signal => return Err(SignalError::UnexpectedSignal(format!("{:?}", signal))),
     }
 
     Ok(())
 }
 
-pub async fn user_connection(ws: WebSocket, users: Users, sessions: Sessions) {
-    println!("establishing client connection... {:?}", ws);
+/// Waits for the client's `Hello` and answers it with `Welcome`. Returns
+/// the client name and the identity it wants to resume on success.
+pub async fn handshake(
+    user: &User,
+    client_ws_rcv: &mut SplitStream<WebSocket>,
+    config: &Config,
+) -> Result<(String, Option<(UserId, ResumeToken)>), SignalError> {
+    let msg = loop {
+        match client_ws_rcv.next().await {
+            Some(Ok(msg)) if msg.is_ping() || msg.is_pong() => continue,
+            Some(Ok(msg)) if !msg.is_close() => break msg,
+            _ => return Err(SignalError::HandshakeRequired),
+        }
+    };
+
+    let signal: Signal = match msg.to_str().map(serde_json::from_str) {
+        Ok(Ok(signal)) => signal,
+        _ => return Err(SignalError::HandshakeRequired),
+    };
+    answer_hello(user, signal, config).await
+}
+
+/// Welcomes a client speaking a supported protocol version.
+async fn answer_hello(
+    user: &User,
+    signal: Signal,
+    config: &Config,
+) -> Result<(String, Option<(UserId, ResumeToken)>), SignalError> {
+    let (protocol_version, client_name, capabilities, resume) = match signal {
+        Signal::Hello {
+            protocol_version,
+            client_name,
+            capabilities,
+            resume,
+        } => (protocol_version, client_name, capabilities, resume),
+        _ => return Err(SignalError::HandshakeRequired),
+    };
+
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(SignalError::IncompatibleProtocol {
+            client: protocol_version,
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+    }
+
+    let features = SERVER_CAPABILITIES
+        .iter()
+        .filter(|capability| capabilities.contains(capability))
+        .cloned()
+        .collect();
+    let welcome = Signal::Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        features,
+        limits: ServerLimits {
+            max_sessions: config.max_sessions,
+            max_viewers: config.max_viewers,
+        },
+    };
+    send_signal(user, welcome).await?;
+
+    Ok((client_name, resume))
+}
+
+/// Hands the user over to a new connection if the token matches. Returns the
+/// session the user is still part of.
+pub async fn resume_user(
+    user_id: &UserId,
+    resume_token: &ResumeToken,
+    new_user: &User,
+    users: &Users,
+) -> Result<Option<SessionId>, SignalError> {
+    match users.lock().await.get_mut(user_id) {
+        Some(user) if user.resume_token == *resume_token => {
+            user.sender = new_user.sender.clone();
+            user.connection_id = new_user.connection_id;
+            user.address = new_user.address;
+            user.disconnected = false;
+
+            let sig_msg = Signal::NewUser(user_id.clone(), user.resume_token.clone());
+            send_signal(user, sig_msg).await?;
+            let sig_msg = Signal::Resumed(user.session_id.clone());
+            send_signal(user, sig_msg).await?;
+            Ok(user.session_id.clone())
+        }
+        _ => Err(SignalError::ResumeFailed(user_id.clone())),
+    }
+}
+
+/// Marks the user as disconnected. Unless another connection resumes it
+/// within the grace period, the user is removed together with its session.
+pub async fn disconnect_user(
+    user_id: &UserId,
+    connection_id: Uuid,
+    users: Users,
+    sessions: Sessions,
+    grace_period: Duration,
+) {
+    match users.lock().await.get_mut(user_id) {
+        Some(user) if user.connection_id == connection_id => user.disconnected = true,
+        _ => return,
+    }
+
+    tokio::time::sleep(grace_period).await;
+
+    let mut sessions = sessions.lock().await;
+    let mut users = users.lock().await;
+    match users.get(user_id) {
+        Some(user) if user.connection_id == connection_id && user.disconnected => {}
+        _ => return,
+    }
+    leave_session_locked(user_id, &mut users, &mut sessions).await;
+    users.remove(user_id);
+    info!("Removed user: {:?}", user_id);
+}
+
+pub async fn user_connection(
+    ws: WebSocket,
+    address: Option<IpAddr>,
+    users: Users,
+    sessions: Sessions,
+    config: SharedConfig,
+) {
+    debug!("establishing client connection... {:?}", ws);
 
     let (client_ws_sender, mut client_ws_rcv) = ws.split();
     let (client_sender, client_rcv) = mpsc::unbounded_channel();
@@ -161,64 +792,182 @@ pub async fn user_connection(ws: WebSocket, users: Users, sessions: Sessions) {
 
     tokio::task::spawn(client_rcv.forward(client_ws_sender).map(|res| {
         if let Err(e) = res {
-            println!("error sending websocket msg: {}", e);
+            warn!("error sending websocket msg: {}", e);
         }
     }));
 
-    let user_id = UserId::new(Uuid::new_v4().to_string());
+    let connection_id = Uuid::new_v4();
     let user = User {
         sender: client_sender.clone(),
         session_id: None,
-        user_id: user_id.clone(),
+        user_id: UserId::new(Uuid::new_v4().to_string()),
+        display_name: String::new(),
+        resume_token: ResumeToken::new(Uuid::new_v4().to_string()),
+        connection_id,
+        address,
+        failed_joins: 0,
+        disconnected: false,
     };
 
-    println!("Created new user: {:?}", user_id);
-    users.lock().await.insert(user_id.clone(), user);
-
-    let new_user_signal = Signal::NewUser(user_id.clone());
-    let message = match serde_json::to_string(&new_user_signal) {
-        Ok(msg) => msg,
+    let hello = tokio::time::timeout(
+        config.heartbeat_timeout,
+        handshake(&user, &mut client_ws_rcv, &config),
+    )
+    .await
+    .unwrap_or(Err(SignalError::HandshakeRequired));
+    let (client_name, resume) = match hello {
+        Ok(hello) => hello,
         Err(e) => {
-            println!("error serializing NewUser{:?}: {:?}", new_user_signal, e);
+            warn!("handshake with {:?} failed: {}", user.user_id, e);
+            if let Err(e) = send_signal(&user, Signal::Error(e)).await {
+                warn!("error sending Error signal: {:?}", e);
+            }
             return;
         }
     };
 
-    match client_sender.send(Ok(Message::text(message))) {
-        Err(e) => {
-            println!("error sending NewUser{:?}: {:?}", new_user_signal, e);
+    let resumed = match resume {
+        Some((user_id, resume_token)) => {
+            match resume_user(&user_id, &resume_token, &user, &users).await {
+                Ok(session_id) => {
+                    info!(
+                        "Resumed user: {:?} ({}) in session {:?}",
+                        user_id, client_name, session_id
+                    );
+                    Some(user_id)
+                }
+                Err(e) => {
+                    warn!("error resuming user: {}", e);
+                    if let Err(e) = send_signal(&user, Signal::Error(e)).await {
+                        warn!("error sending Error signal: {:?}", e);
+                    }
+                    None
+                }
+            }
         }
-        _ => {}
-    }
+        None => None,
+    };
+
+    let user_id = match resumed {
+        Some(user_id) => user_id,
+        None => {
+            let user_id = user.user_id.clone();
+            info!("Created new user: {:?} ({})", user_id, client_name);
+            let new_user_signal = Signal::NewUser(user_id.clone(), user.resume_token.clone());
+            if let Err(e) = send_signal(&user, new_user_signal).await {
+                warn!("error sending NewUser: {:?}", e);
+            }
+            users.lock().await.insert(user_id.clone(), user);
+            user_id
+        }
+    };
+
+    // Browsers answer pings on their own, so any incoming frame proves the
+    // connection is still alive.
+    let mut heartbeat = tokio::time::interval(config.heartbeat_interval);
+    let mut last_seen = Instant::now();
+    loop {
+        let res = tokio::select! {
+            res = client_ws_rcv.next() => match res {
+                Some(res) => res,
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > config.heartbeat_timeout {
+                    warn!("user {:?} missed heartbeats, disconnecting", user_id);
+                    let _ = client_sender.send(Ok(Message::close()));
+                    break;
+                }
+                if client_sender.send(Ok(Message::ping(Vec::new()))).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        last_seen = Instant::now();
 
-    while let Some(res) = client_ws_rcv.next().await {
         let msg = match res {
             Ok(msg) => msg,
             Err(e) => {
-                println!("error receving message: {}", e);
+                warn!("error receving message: {}", e);
                 break;
             }
         };
-        match handle_message(&user_id, &msg, users.clone(), sessions.clone()).await {
+        if msg.is_close() {
+            break;
+        }
+        if msg.is_ping() || msg.is_pong() {
+            continue;
+        }
+        let result = handle_message(
+            &user_id,
+            &msg,
+            users.clone(),
+            sessions.clone(),
+            config.clone(),
+        )
+        .await;
+        match result {
             Ok(()) => {
-                println!("successfully hadnled message")
+                debug!("successfully hadnled message")
             }
             Err(e) => {
-                println!("error hadnling message: {:?}, msg: {:?}", e, msg);
+                warn!("error hadnling message: {:?}, msg: {:?}", e, msg);
+                if let Some(user) = users.lock().await.get(&user_id) {
+                    if let Err(e) = send_signal(user, Signal::Error(e)).await {
+                        warn!("error sending Error signal: {:?}", e);
+                    }
+                }
             }
         }
     }
 
-    users.lock().await.remove(&user_id);
+    disconnect_user(
+        &user_id,
+        connection_id,
+        users,
+        sessions,
+        config.resume_grace_period,
+    )
+    .await;
 }
 
 pub async fn ws_handler(
     ws: warp::ws::Ws,
+    remote: Option<SocketAddr>,
+    headers: HeaderMap,
     users: Users,
     sessions: Sessions,
+    config: SharedConfig,
 ) -> Result<impl Reply, Rejection> {
-    println!("Hadling WebSocket...");
-    Ok(ws.on_upgrade(move |socket| user_connection(socket, users, sessions)))
+    debug!("Hadling WebSocket...");
+    let address = client_address(remote, &headers, &config);
+    Ok(ws.on_upgrade(move |socket| user_connection(socket, address, users, sessions, config)))
+}
+
+/// The address bans apply to. Behind a reverse proxy, the remote address is
+/// the proxy's, so it is taken from the header the proxy sets instead.
+fn client_address(
+    remote: Option<SocketAddr>,
+    headers: &HeaderMap,
+    config: &Config,
+) -> Option<IpAddr> {
+    if !config.ban_addresses {
+        return None;
+    }
+    match config.client_address_header.as_ref() {
+        // Proxies append the address they received the request from
+        Some(name) => headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok(),
+        None => remote.map(|remote| remote.ip()),
+    }
 }
 
 fn with_users(users: Users) -> impl Filter<Extract = (Users,), Error = Infallible> + Clone {
@@ -231,23 +980,515 @@ fn with_sessions(
     warp::any().map(move || sessions.clone())
 }
 
+fn with_config(
+    config: SharedConfig,
+) -> impl Filter<Extract = (SharedConfig,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
 #[tokio::main]
 async fn main() {
+    let config: SharedConfig = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("error loading configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+    debug!("Loaded configuration: {:#?}", config);
+
     let users: Users = Arc::new(Mutex::new(HashMap::new()));
     let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
 
-    println!("Configuring websocket route");
-    let ws_route = warp::any()
+    info!("Configuring websocket route");
+    let ws_route = warp::path("ws")
+        .and(warp::path::end())
         .and(warp::ws())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
         .and(with_users(users.clone()))
         .and(with_sessions(sessions.clone()))
+        .and(with_config(config.clone()))
         .and_then(ws_handler);
-    let routes = ws_route.with(warp::cors().allow_any_origin());
-    println!("Starting server");
-    warp::serve(routes)
-        .tls()
-        .cert_path("cert.crt")
-        .key_path("key.rsa")
-        .run(([0, 0, 0, 0], 9999))
+    let routes = match config.static_dir.as_ref() {
+        Some(static_dir) => {
+            info!("Serving frontend from {:?}", static_dir);
+            ws_route
+                .or(warp::fs::dir(static_dir.clone()))
+                .map(|reply| Box::new(reply) as Box<dyn Reply>)
+                .boxed()
+        }
+        None => ws_route
+            .map(|reply| Box::new(reply) as Box<dyn Reply>)
+            .boxed(),
+    };
+    info!("Starting server on {}", config.address);
+    let server = warp::serve(routes);
+    match config.tls.as_ref() {
+        Some(tls) => {
+            server
+                .tls()
+                .cert_path(&tls.cert)
+                .key_path(&tls.key)
+                .run(config.address)
+                .await
+        }
+        None => server.run(config.address).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_id(id: &str) -> UserId {
+        UserId::new(id.to_string())
+    }
+
+    fn chat() -> Signal {
+        Signal::Chat(SessionId::default(), UserId::default(), String::new())
+    }
+
+    fn offer() -> Signal {
+        let offer = SessionDescription::new(SdpType::Offer, "v=0".to_string());
+        Signal::VideoOffer(SessionId::default(), UserId::default(), offer)
+    }
+
+    type Receiver = mpsc::UnboundedReceiver<Result<Message, warp::Error>>;
+
+    /// The receiver stands in for the user's web socket.
+    fn user(id: &str, address: Option<IpAddr>) -> (User, Receiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let user = User {
+            sender,
+            session_id: None,
+            user_id: user_id(id),
+            display_name: id.to_string(),
+            resume_token: ResumeToken::default(),
+            connection_id: Uuid::new_v4(),
+            address,
+            failed_joins: 0,
+            disconnected: false,
+        };
+        (user, receiver)
+    }
+
+    /// Adds a connected user taking part in the session.
+    fn connect(users: &mut HashMap<UserId, User>, id: &str, session_id: &str) -> Receiver {
+        let (mut user, receiver) = user(id, None);
+        user.session_id = Some(SessionId::new(session_id.to_string()));
+        users.insert(user_id(id), user);
+        receiver
+    }
+
+    fn config() -> SharedConfig {
+        Arc::new(Config {
+            address: ([127, 0, 0, 1], 0).into(),
+            tls: None,
+            static_dir: None,
+            log_level: log::LevelFilter::Off,
+            max_sessions: None,
+            max_viewers: None,
+            ban_addresses: false,
+            client_address_header: None,
+            resume_grace_period: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(15),
+            heartbeat_timeout: Duration::from_secs(45),
+        })
+    }
+
+    async fn handle(
+        user_id: &UserId,
+        signal: Signal,
+        users: &Users,
+        sessions: &Sessions,
+    ) -> Result<(), SignalError> {
+        let msg = Message::text(serde_json::to_string(&signal).unwrap());
+        handle_message(user_id, &msg, users.clone(), sessions.clone(), config()).await
+    }
+
+    /// A session hosted by `id` without anyone else in it.
+    fn hosted_by(id: &str) -> Session {
+        let mut roles = HashMap::new();
+        roles.insert(user_id(id), Role::Presenter);
+        Session {
+            host: user_id(id),
+            roles,
+            ..session()
+        }
+    }
+
+    /// The host presents, `cohost` and `viewer` watch.
+    fn session() -> Session {
+        let mut roles = HashMap::new();
+        roles.insert(user_id("host"), Role::Presenter);
+        roles.insert(user_id("cohost"), Role::CoHost);
+        roles.insert(user_id("viewer"), Role::Viewer);
+        Session {
+            host: user_id("host"),
+            roles,
+            password: None,
+            waiting_room: false,
+            pending: HashSet::new(),
+            banned: HashSet::new(),
+            banned_addresses: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn is_full_does_not_count_the_host() {
+        let session = session();
+        assert!(!session.is_full(None));
+        assert!(!session.is_full(Some(3)));
+        assert!(session.is_full(Some(2)));
+    }
+
+    #[test]
+    fn host_and_cohosts_moderate() {
+        let session = session();
+        assert!(session.can_moderate(&user_id("host")));
+        assert!(session.can_moderate(&user_id("cohost")));
+        assert!(!session.can_moderate(&user_id("viewer")));
+        assert!(!session.can_moderate(&user_id("stranger")));
+    }
+
+    #[test]
+    fn media_is_relayed_to_and_from_the_presenter_only() {
+        let session = session();
+        assert!(session.can_relay(&user_id("host"), &user_id("viewer"), &offer()));
+        assert!(session.can_relay(&user_id("viewer"), &user_id("host"), &offer()));
+        assert!(!session.can_relay(&user_id("viewer"), &user_id("cohost"), &offer()));
+    }
+
+    #[test]
+    fn chat_is_relayed_between_any_participants() {
+        let session = session();
+        assert!(session.can_relay(&user_id("viewer"), &user_id("cohost"), &chat()));
+        assert!(!session.can_relay(&user_id("viewer"), &user_id("viewer"), &chat()));
+        assert!(!session.can_relay(&user_id("viewer"), &user_id("stranger"), &chat()));
+        assert!(!session.can_relay(&user_id("stranger"), &user_id("viewer"), &chat()));
+    }
+
+    #[tokio::test]
+    async fn wrong_password_keeps_the_current_session() {
+        let mut users = HashMap::new();
+        let _joiner = connect(&mut users, "joiner", "current");
+        let _host = connect(&mut users, "host", "locked");
+        let mut sessions = HashMap::new();
+        sessions.insert(SessionId::new("current".to_string()), hosted_by("joiner"));
+        let locked = Session {
+            password: Some("secret".to_string()),
+            ..hosted_by("host")
+        };
+        sessions.insert(SessionId::new("locked".to_string()), locked);
+        let users: Users = Arc::new(Mutex::new(users));
+        let sessions: Sessions = Arc::new(Mutex::new(sessions));
+
+        let options = JoinOptions {
+            password: Some("guess".to_string()),
+            display_name: String::new(),
+        };
+        let signal = Signal::SessionJoin(SessionId::new("locked".to_string()), options);
+        let result = handle(&user_id("joiner"), signal, &users, &sessions).await;
+
+        assert!(matches!(result, Err(SignalError::AccessDenied(_))));
+        let current = SessionId::new("current".to_string());
+        assert!(sessions.lock().await.contains_key(&current));
+        let joiner_session = users.lock().await[&user_id("joiner")].session_id.clone();
+        assert_eq!(joiner_session, Some(current));
+    }
+
+    #[tokio::test]
+    async fn session_limit_keeps_the_current_session() {
+        let mut users = HashMap::new();
+        let _viewer = connect(&mut users, "viewer", "current");
+        let _host = connect(&mut users, "host", "current");
+        let mut current = hosted_by("host");
+        current.roles.insert(user_id("viewer"), Role::Viewer);
+        let mut sessions = HashMap::new();
+        sessions.insert(SessionId::new("current".to_string()), current);
+        let users: Users = Arc::new(Mutex::new(users));
+        let sessions: Sessions = Arc::new(Mutex::new(sessions));
+        let config = Arc::new(Config {
+            max_sessions: Some(1),
+            ..Arc::try_unwrap(config()).unwrap()
+        });
+
+        let msg =
+            Message::text(serde_json::to_string(&Signal::SessionNew(Default::default())).unwrap());
+        let result = handle_message(
+            &user_id("viewer"),
+            &msg,
+            users.clone(),
+            sessions.clone(),
+            config,
+        )
+        .await;
+
+        assert!(matches!(result, Err(SignalError::SessionLimitReached)));
+        let current = SessionId::new("current".to_string());
+        assert!(sessions.lock().await[&current].is_participant(&user_id("viewer")));
+        let viewer_session = users.lock().await[&user_id("viewer")].session_id.clone();
+        assert_eq!(viewer_session, Some(current));
+    }
+
+    #[test]
+    fn ban_covers_the_user() {
+        let mut session = session();
+        let (banned, _) = user("banned", None);
+        let (other, _) = user("other", None);
+        session.ban(&banned);
+        assert!(session.is_banned(&banned));
+        assert!(!session.is_banned(&other));
+    }
+
+    #[test]
+    fn ban_covers_the_address() {
+        let mut session = session();
+        let (banned, _) = user("banned", Some([10, 0, 0, 5].into()));
+        let (reconnected, _) = user("reconnected", Some([10, 0, 0, 5].into()));
+        let (other, _) = user("other", Some([10, 0, 0, 6].into()));
+        session.ban(&banned);
+        assert!(session.is_banned(&reconnected));
+        assert!(!session.is_banned(&other));
+    }
+
+    #[test]
+    fn ban_skips_loopback_addresses() {
+        let mut session = session();
+        let (banned, _) = user("banned", Some([127, 0, 0, 1].into()));
+        let (other, _) = user("other", Some([127, 0, 0, 1].into()));
+        session.ban(&banned);
+        assert!(session.is_banned(&banned));
+        assert!(!session.is_banned(&other));
+    }
+
+    #[test]
+    fn client_address_is_only_known_with_address_bans() {
+        let remote = Some(([192, 168, 1, 2], 4000).into());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.3".parse().unwrap());
+        let mut config = Arc::try_unwrap(config()).unwrap();
+        assert_eq!(client_address(remote, &headers, &config), None);
+
+        config.ban_addresses = true;
+        let address = client_address(remote, &headers, &config);
+        assert_eq!(address, Some([192, 168, 1, 2].into()));
+
+        config.client_address_header = Some("X-Forwarded-For".to_string());
+        let address = client_address(remote, &headers, &config);
+        assert_eq!(address, Some([10, 0, 0, 3].into()));
+        assert_eq!(client_address(remote, &HeaderMap::new(), &config), None);
+    }
+
+    #[test]
+    fn join_needs_the_session_password() {
+        let session = Session {
+            password: Some("secret".to_string()),
+            ..session()
+        };
+        let session_id = SessionId::default();
+        let (joiner, _) = user("joiner", None);
+        let check = |password: Option<&str>| {
+            let password = password.map(|password| password.to_string());
+            session.check_join(&session_id, &joiner, password.as_ref(), None)
+        };
+        assert!(check(Some("secret")).is_ok());
+        assert!(matches!(
+            check(Some("guess")),
+            Err(SignalError::AccessDenied(_))
+        ));
+        assert!(matches!(check(None), Err(SignalError::AccessDenied(_))));
+    }
+
+    #[test]
+    fn join_without_password() {
+        let session = session();
+        let (joiner, _) = user("joiner", None);
+        let password = Some("anything".to_string());
+        let session_id = SessionId::default();
+        assert!(session.check_join(&session_id, &joiner, None, None).is_ok());
+        assert!(session
+            .check_join(&session_id, &joiner, password.as_ref(), None)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_joins_slow_down_the_next_join() {
+        let mut users = HashMap::new();
+        let _host = connect(&mut users, "host", "locked");
+        let (joiner, _joiner_receiver) = user("joiner", None);
+        users.insert(user_id("joiner"), joiner);
+        let locked = Session {
+            password: Some("secret".to_string()),
+            ..hosted_by("host")
+        };
+        let locked_id = SessionId::new("locked".to_string());
+        let mut sessions = HashMap::new();
+        sessions.insert(locked_id.clone(), locked);
+        let users: Users = Arc::new(Mutex::new(users));
+        let sessions: Sessions = Arc::new(Mutex::new(sessions));
+        let join = |session_id: &SessionId, password: &str| {
+            let options = JoinOptions {
+                password: Some(password.to_string()),
+                display_name: String::new(),
+            };
+            Signal::SessionJoin(session_id.clone(), options)
+        };
+        let failed_joins = || async { users.lock().await[&user_id("joiner")].failed_joins };
+
+        let unknown = SessionId::new("unknown".to_string());
+        assert!(
+            handle(&user_id("joiner"), join(&unknown, ""), &users, &sessions)
+                .await
+                .is_ok()
+        );
+        assert_eq!(failed_joins().await, 1);
+
+        let started = Instant::now();
+        let result = handle(
+            &user_id("joiner"),
+            join(&locked_id, "guess"),
+            &users,
+            &sessions,
+        )
         .await;
+        assert!(matches!(result, Err(SignalError::AccessDenied(_))));
+        assert!(started.elapsed() >= FAILED_JOIN_DELAY);
+        assert_eq!(failed_joins().await, 2);
+
+        let result = handle(
+            &user_id("joiner"),
+            join(&locked_id, "secret"),
+            &users,
+            &sessions,
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(failed_joins().await, 0);
+    }
+
+    /// Signals sent to the user so far.
+    fn received(receiver: &mut Receiver) -> Vec<Signal> {
+        let mut signals = Vec::new();
+        while let Ok(Ok(message)) = receiver.try_recv() {
+            signals.push(serde_json::from_str(message.to_str().unwrap()).unwrap());
+        }
+        signals
+    }
+
+    #[tokio::test]
+    async fn waiting_room_holds_joiners_until_accepted() {
+        let mut users = HashMap::new();
+        let mut host = connect(&mut users, "host", "room");
+        let (joiner, mut joiner_receiver) = user("joiner", None);
+        users.insert(user_id("joiner"), joiner);
+        let room = Session {
+            waiting_room: true,
+            ..hosted_by("host")
+        };
+        let room_id = SessionId::new("room".to_string());
+        let mut sessions = HashMap::new();
+        sessions.insert(room_id.clone(), room);
+        let users: Users = Arc::new(Mutex::new(users));
+        let sessions: Sessions = Arc::new(Mutex::new(sessions));
+
+        let signal = Signal::SessionJoin(room_id.clone(), JoinOptions::default());
+        handle(&user_id("joiner"), signal, &users, &sessions)
+            .await
+            .unwrap();
+        assert!(sessions.lock().await[&room_id]
+            .pending
+            .contains(&user_id("joiner")));
+        assert!(!sessions.lock().await[&room_id].is_participant(&user_id("joiner")));
+        let signals = received(&mut joiner_receiver);
+        assert!(matches!(signals[..], [Signal::SessionJoinPending(_)]));
+        let signals = received(&mut host);
+        assert!(matches!(signals[..], [Signal::JoinRequest(..)]));
+
+        let signal = Signal::JoinAccept(user_id("joiner"));
+        handle(&user_id("host"), signal, &users, &sessions)
+            .await
+            .unwrap();
+        assert!(sessions.lock().await[&room_id].pending.is_empty());
+        assert!(sessions.lock().await[&room_id].is_participant(&user_id("joiner")));
+        let signals = received(&mut joiner_receiver);
+        assert!(matches!(
+            signals.first(),
+            Some(Signal::SessionJoinSuccess(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_moderators_answer_join_requests() {
+        let mut users = HashMap::new();
+        let _host = connect(&mut users, "host", "room");
+        let _viewer = connect(&mut users, "viewer", "room");
+        let _joiner = connect(&mut users, "joiner", "room");
+        let mut room = hosted_by("host");
+        room.roles.insert(user_id("viewer"), Role::Viewer);
+        room.pending.insert(user_id("joiner"));
+        let room_id = SessionId::new("room".to_string());
+        let mut sessions = HashMap::new();
+        sessions.insert(room_id.clone(), room);
+        let users: Users = Arc::new(Mutex::new(users));
+        let sessions: Sessions = Arc::new(Mutex::new(sessions));
+
+        let signal = Signal::JoinAccept(user_id("joiner"));
+        let result = handle(&user_id("viewer"), signal, &users, &sessions).await;
+        assert!(matches!(result, Err(SignalError::PermissionDenied)));
+        assert!(sessions.lock().await[&room_id]
+            .pending
+            .contains(&user_id("joiner")));
+    }
+
+    fn hello(protocol_version: u32) -> Signal {
+        Signal::Hello {
+            protocol_version,
+            client_name: "test".to_string(),
+            capabilities: vec![Capability::Resume, Capability::Unknown],
+            resume: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_accepts_supported_versions() {
+        let (user, mut receiver) = user("user", None);
+        let result = answer_hello(&user, hello(PROTOCOL_VERSION), &config()).await;
+        assert_eq!(result.unwrap().0, "test");
+        match received(&mut receiver).as_slice() {
+            [Signal::Welcome {
+                protocol_version,
+                features,
+                ..
+            }] => {
+                assert_eq!(*protocol_version, PROTOCOL_VERSION);
+                assert_eq!(features, &vec![Capability::Resume]);
+            }
+            signals => panic!("expected Welcome, got {:?}", signals),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_versions() {
+        let (user, mut receiver) = user("user", None);
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1].iter() {
+            let result = answer_hello(&user, hello(*version), &config()).await;
+            assert!(matches!(
+                result,
+                Err(SignalError::IncompatibleProtocol { client, .. }) if client == *version
+            ));
+        }
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_starts_with_hello() {
+        let (user, _receiver) = user("user", None);
+        let result = answer_hello(&user, Signal::SessionNew(Default::default()), &config()).await;
+        assert!(matches!(result, Err(SignalError::HandshakeRequired)));
+    }
 }
@@ -0,0 +1,86 @@
+use rand::{seq::SliceRandom, Rng};
+use signalling_protocol::SessionId;
+use std::collections::HashMap;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "blue", "brave", "calm", "clever", "coral", "crisp", "eager", "fancy",
+    "gentle", "golden", "green", "happy", "jolly", "keen", "lively", "lucky", "mellow", "misty",
+    "noble", "olive", "proud", "quick", "quiet", "rapid", "red", "silver", "sunny", "swift",
+    "violet", "witty",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "bison", "camel", "cobra", "crane", "dingo", "eagle", "falcon", "ferret",
+    "gecko", "heron", "ibis", "koala", "lemur", "llama", "lynx", "marten", "moose", "newt",
+    "otter", "panda", "puffin", "quokka", "raven", "robin", "salmon", "tapir", "tiger", "walrus",
+    "wombat", "yak",
+];
+
+/// Attempts before allowing longer numbers, so codes stay short while there
+/// are few sessions.
+const ATTEMPTS_PER_LENGTH: usize = 16;
+
+/// Generates a readable session code like `blue-otter-42` that no session in
+/// `sessions` uses yet.
+pub fn new_session_code<T>(sessions: &HashMap<SessionId, T>) -> SessionId {
+    let mut rng = rand::thread_rng();
+    let mut max_number = 100;
+    loop {
+        for _ in 0..ATTEMPTS_PER_LENGTH {
+            let code = format!(
+                "{}-{}-{}",
+                ADJECTIVES.choose(&mut rng).unwrap(),
+                ANIMALS.choose(&mut rng).unwrap(),
+                rng.gen_range(0..max_number)
+            );
+            let session_id = SessionId::new(code);
+            if !sessions.contains_key(&session_id) {
+                return session_id;
+            }
+        }
+        max_number *= 10;
+    }
+}
+
+/// Codes are typed by hand, so be lenient about case and surrounding spaces.
+pub fn normalize_session_code(session_id: &SessionId) -> SessionId {
+    SessionId::new(session_id.value().trim().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_adjective_animal_number() {
+        let code = new_session_code::<()>(&HashMap::new());
+        let parts: Vec<&str> = code.value().split('-').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(ADJECTIVES.contains(&parts[0]));
+        assert!(ANIMALS.contains(&parts[1]));
+        assert!(parts[2].parse::<u32>().unwrap() < 100);
+    }
+
+    #[test]
+    fn allows_longer_numbers_once_short_codes_are_taken() {
+        let mut sessions = HashMap::new();
+        for adjective in ADJECTIVES {
+            for animal in ANIMALS {
+                for number in 0..100 {
+                    let code = format!("{}-{}-{}", adjective, animal, number);
+                    sessions.insert(SessionId::new(code), ());
+                }
+            }
+        }
+        let code = new_session_code(&sessions);
+        assert!(!sessions.contains_key(&code));
+        let number: u32 = code.value().rsplit('-').next().unwrap().parse().unwrap();
+        assert!((100..1000).contains(&number));
+    }
+
+    #[test]
+    fn normalizes_case_and_spaces() {
+        let code = normalize_session_code(&SessionId::new("  Blue-OTTER-42 \n".to_string()));
+        assert_eq!(code, SessionId::new("blue-otter-42".to_string()));
+    }
+}
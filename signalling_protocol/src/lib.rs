@@ -1,5 +1,18 @@
 use serde::{Deserialize, Serialize};
 
+/// Version of the signalling protocol implemented by this crate. Bump it on
+/// every change that older peers can not understand.
+///
+/// History:
+/// - 9: first released version, with the `Hello`/`Welcome` handshake, resume
+///   tokens, session passwords, the waiting room, kick and ban, participant
+///   roles, chat and the host in `SessionJoinSuccess`. Lower versions were
+///   only spoken by development builds and are not supported.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Longest chat message in characters the server relays.
+pub const MAX_CHAT_MESSAGE_LEN: usize = 4096;
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
 pub struct SessionId(String);
 
@@ -24,35 +37,516 @@ impl UserId {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Secret handed out with `Signal::NewUser` that lets a client reclaim its
+/// `UserId` after the web socket dropped.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct ResumeToken(String);
+
+impl ResumeToken {
+    pub fn new(string: String) -> Self {
+        ResumeToken(string)
+    }
+    pub fn value(&self) -> &String {
+        &self.0
+    }
+}
+
+/// Optional features a peer can announce in `Signal::Hello`. The server
+/// answers with the subset it supports as well.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Capability {
+    MultipleViewers,
+    EndOfCandidates,
+    Resume,
+    /// Any capability this version of the protocol does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ServerLimits {
+    pub max_sessions: Option<usize>,
+    pub max_viewers: Option<usize>,
+}
+
+/// Part a participant plays in a session. Exactly one participant presents
+/// at a time, the session's creator to begin with. The creator can always
+/// moderate, whatever its role.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Role {
+    /// Sends its screen to everyone else.
+    Presenter,
+    /// Only receives media but can moderate the session.
+    CoHost,
+    /// Only receives media.
+    Viewer,
+}
+
+/// Settings chosen by the host in `Signal::SessionNew`.
+#[derive(Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SessionOptions {
+    /// Has to be presented by everyone joining the session.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Joiners wait until the host accepts them.
+    #[serde(default)]
+    pub waiting_room: bool,
+    #[serde(default)]
+    pub display_name: String,
+}
+
+impl std::fmt::Debug for SessionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionOptions")
+            .field("password", &self.password.as_ref().map(|_| "..."))
+            .field("waiting_room", &self.waiting_room)
+            .field("display_name", &self.display_name)
+            .finish()
+    }
+}
+
+/// Sent by a client in `Signal::SessionJoin`.
+#[derive(Default, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct JoinOptions {
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Name shown to the host in the waiting room.
+    #[serde(default)]
+    pub display_name: String,
+}
+
+impl std::fmt::Debug for JoinOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinOptions")
+            .field("password", &self.password.as_ref().map(|_| "..."))
+            .field("display_name", &self.display_name)
+            .finish()
+    }
+}
+
+/// How a client resolves offers colliding with its own, assigned per peer
+/// by the server. The participant that joined first is impolite towards
+/// later ones, so the two ends of a connection always differ.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum NegotiationRole {
+    /// Rolls back its own offer to accept the peer's.
+    Polite,
+    /// Ignores the peer's offer and keeps its own.
+    Impolite,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SdpType {
+    Offer,
+    Pranswer,
+    Answer,
+    Rollback,
+}
+
+/// Mirrors the browser's `RTCSessionDescriptionInit`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub sdp_type: SdpType,
+    pub sdp: String,
+}
+
+impl SessionDescription {
+    pub fn new(sdp_type: SdpType, sdp: String) -> Self {
+        SessionDescription { sdp_type, sdp }
+    }
+
+    pub fn validate(&self, expected: SdpType) -> Result<(), String> {
+        if self.sdp_type != expected {
+            return Err(format!(
+                "expected {:?} description, got {:?}",
+                expected, self.sdp_type
+            ));
+        }
+        if self.sdp_type != SdpType::Rollback && !self.sdp.starts_with("v=") {
+            return Err("sdp does not start with a version line".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Mirrors the browser's `RTCIceCandidateInit`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    pub sdp_m_line_index: Option<u16>,
+}
+
+impl IceCandidate {
+    /// The end of candidates is sent as `None`, never as an empty candidate.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.candidate.starts_with("candidate:") {
+            return Err(format!("invalid candidate line: {:?}", self.candidate));
+        }
+        if self.sdp_mid.is_none() && self.sdp_m_line_index.is_none() {
+            return Err("candidate has neither sdp_mid nor sdp_m_line_index".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Control messages on the file transfer data channel. The content follows
+/// as binary chunks, each prefixed with the transfer id in big endian.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum FileMessage {
+    /// `sha256` is the hex encoded digest of the whole file.
+    Start {
+        id: u32,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// The sender gave up on the transfer.
+    CancelSend { id: u32 },
+    /// The receiver does not want the rest of the file.
+    CancelReceive { id: u32 },
+}
+
+/// Messages on the remote control data channel between a viewer and the
+/// presenter. Only the viewer granted control may send `Input`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RemoteControlMessage {
+    /// A viewer asks for control.
+    Request,
+    /// The presenter's answer to `Request`.
+    Grant,
+    Deny,
+    /// Ends control, sent by either side.
+    Release,
+    Input(RemoteInput),
+}
+
+/// Input events of the viewer in control. Positions are normalized to the
+/// shared stream, (0, 0) being its top left and (1, 1) its bottom right
+/// corner.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RemoteInput {
+    PointerMove {
+        x: f64,
+        y: f64,
+    },
+    PointerDown {
+        x: f64,
+        y: f64,
+        button: PointerButton,
+    },
+    PointerUp {
+        x: f64,
+        y: f64,
+        button: PointerButton,
+    },
+    /// Deltas are in CSS pixels of the viewer's page.
+    Wheel {
+        x: f64,
+        y: f64,
+        delta_x: f64,
+        delta_y: f64,
+    },
+    /// `key` and `code` as defined for DOM keyboard events.
+    KeyDown {
+        key: String,
+        code: String,
+        modifiers: KeyModifiers,
+    },
+    KeyUp {
+        key: String,
+        code: String,
+        modifiers: KeyModifiers,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum PointerButton {
+    Primary,
+    Auxiliary,
+    Secondary,
+    /// DOM button number of any further button.
+    Other(i16),
+}
+
+impl From<i16> for PointerButton {
+    /// Maps the DOM `MouseEvent.button` number.
+    fn from(button: i16) -> Self {
+        match button {
+            0 => PointerButton::Primary,
+            1 => PointerButton::Auxiliary,
+            2 => PointerButton::Secondary,
+            other => PointerButton::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct KeyModifiers {
+    pub shift: bool,
+    pub control: bool,
+    pub alt: bool,
+    pub meta: bool,
+}
+
+/// Messages on the annotation data channel. Viewers are only connected to
+/// the presenter, who forwards them to everyone else.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum AnnotationMessage {
+    Draw(Annotation),
+    /// Removes every annotation, offered to the host only.
+    ClearAll,
+}
+
+/// A finished drawing on the shared screen. Points are normalized like
+/// `RemoteInput` positions.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Annotation {
+    pub kind: AnnotationKind,
+    pub points: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum AnnotationKind {
+    /// Freehand line.
+    Stroke,
+    /// From the first to the last point.
+    Arrow,
+    /// Wide translucent freehand line.
+    Highlight,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum SignalError {
+    UnknownSession(SessionId),
+    UnknownUser(UserId),
+    NotParticipant(SessionId),
+    PeerUnavailable(UserId),
+    MalformedPayload(String),
+    UnexpectedSignal(String),
+    SessionLimitReached,
+    SessionFull(SessionId),
+    AccessDenied(SessionId),
+    JoinDenied(SessionId),
+    Kicked(SessionId),
+    Banned(SessionId),
+    NotInSession,
+    PermissionDenied,
+    HandshakeRequired,
+    ResumeFailed(UserId),
+    IncompatibleProtocol { client: u32, min: u32, max: u32 },
+    Internal(String),
+}
+
+impl std::fmt::Display for SignalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownSession(id) => write!(f, "unknown session {}", id.value()),
+            Self::UnknownUser(id) => write!(f, "unknown user {}", id.value()),
+            Self::NotParticipant(id) => write!(f, "not a participant of session {}", id.value()),
+            Self::PeerUnavailable(id) => write!(f, "peer {} is unavailable", id.value()),
+            Self::MalformedPayload(e) => write!(f, "malformed payload: {}", e),
+            Self::UnexpectedSignal(signal) => write!(f, "unexpected signal: {}", signal),
+            Self::SessionLimitReached => write!(f, "session limit reached"),
+            Self::SessionFull(id) => write!(f, "session {} is full", id.value()),
+            Self::AccessDenied(id) => write!(f, "wrong password for session {}", id.value()),
+            Self::JoinDenied(id) => write!(f, "host denied joining session {}", id.value()),
+            Self::Kicked(id) => write!(f, "removed from session {} by the host", id.value()),
+            Self::Banned(id) => write!(f, "banned from session {}", id.value()),
+            Self::NotInSession => write!(f, "not in a session"),
+            Self::PermissionDenied => write!(f, "not allowed in this session"),
+            Self::HandshakeRequired => write!(f, "expected Hello as the first signal"),
+            Self::ResumeFailed(id) => write!(f, "can not resume user {}", id.value()),
+            Self::IncompatibleProtocol { client, min, max } => write!(
+                f,
+                "protocol version {} is not supported, server accepts {} to {}",
+                client, min, max
+            ),
+            Self::Internal(e) => write!(f, "internal server error: {}", e),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Signal {
-    NewUser(UserId),
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        capabilities: Vec<Capability>,
+        /// Identity of a previous connection to take over.
+        #[serde(default)]
+        resume: Option<(UserId, ResumeToken)>,
+    },
+    Welcome {
+        protocol_version: u32,
+        features: Vec<Capability>,
+        limits: ServerLimits,
+    },
+    NewUser(UserId, ResumeToken),
+    /// Sent after `NewUser` when a previous connection was taken over,
+    /// carries the session the user is still part of.
+    Resumed(Option<SessionId>),
 
-    SessionNew,
+    SessionNew(SessionOptions),
     SessionCreated(SessionId),
-    SessionJoin(SessionId),
-    SessionJoinSuccess(SessionId),
+    SessionJoin(SessionId, JoinOptions),
+    /// The joiner is in the waiting room until the host answers.
+    SessionJoinPending(SessionId),
+    /// Carries the host of the session.
+    SessionJoinSuccess(SessionId, UserId),
     SessionJoinError(SessionId),
+    /// Carries the display name of the participant and the role the
+    /// receiving client takes when negotiating with it. A joiner receives
+    /// one for everyone already in the session.
+    PeerJoined(SessionId, UserId, String, NegotiationRole),
+    SessionLeave(SessionId),
+    SessionEnded(SessionId),
+    PeerLeft(SessionId, UserId),
 
-    VideoOffer(SessionId, String),
-    VideoAnswer(SessionId, String),
-    ICECandidate(SessionId, String),
+    // Waiting room: the server asks the moderators about every joiner, who
+    // is identified by its display name.
+    JoinRequest(UserId, String),
+    JoinAccept(UserId),
+    JoinDeny(UserId),
+    // Moderators only. A banned user can not rejoin for the session's
+    // lifetime, not even from a new connection on the same address.
+    Kick(UserId),
+    Ban(UserId),
+
+    // Viewers and co-hosts negotiate receive-only connections with the
+    // presenter, so handing presenter rights on only replaces those.
+    RoleChanged(SessionId, UserId, Role),
+    /// Moderators and the presenter only.
+    SetPresenter(UserId),
+    /// Host only. Grants or revokes moderation rights.
+    SetCoHost(UserId, bool),
+
+    // The `UserId` is the remote peer: the destination when sent by a
+    // client and the source when relayed by the server.
+    // A `None` ICE candidate marks the end of candidates.
+    VideoOffer(SessionId, UserId, SessionDescription),
+    VideoAnswer(SessionId, UserId, SessionDescription),
+    ICECandidate(SessionId, UserId, Option<IceCandidate>),
     ICEError(SessionId, String),
+
+    /// Chat message relayed by the server until a data channel to the peer
+    /// is open. Same `UserId` convention as above.
+    Chat(SessionId, UserId, String),
+
+    Error(SignalError),
 }
 
 impl std::fmt::Debug for Signal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::NewUser(id) => write!(f, "NewUser: {:?}", id),
-            Self::SessionNew => write!(f, "SessionNew"),
+            Self::Hello {
+                protocol_version,
+                client_name,
+                capabilities,
+                ..
+            } => write!(
+                f,
+                "Hello: v{} {:?} {:?}",
+                protocol_version, client_name, capabilities
+            ),
+            Self::Welcome {
+                protocol_version,
+                features,
+                limits,
+            } => write!(
+                f,
+                "Welcome: v{} {:?} {:?}",
+                protocol_version, features, limits
+            ),
+            Self::NewUser(id, _) => write!(f, "NewUser: {:?}", id),
+            Self::Resumed(id) => write!(f, "Resumed: {:?}", id),
+            Self::SessionNew(options) => write!(f, "SessionNew: {:?}", options),
             Self::SessionCreated(id) => write!(f, "SessionCreated: {:?}", id),
-            Self::SessionJoin(id) => write!(f, "SessionJoin: {:?}", id),
-            Self::SessionJoinSuccess(id) => write!(f, "SessionJoinSuccess: {:?}", id),
+            Self::SessionJoin(id, options) => write!(f, "SessionJoin: {:?}, {:?}", id, options),
+            Self::SessionJoinPending(id) => write!(f, "SessionJoinPending: {:?}", id),
+            Self::SessionJoinSuccess(id, host) => {
+                write!(f, "SessionJoinSuccess: {:?}, {:?}", id, host)
+            }
             Self::SessionJoinError(id) => write!(f, "SessionJoinError: {:?}", id),
-            Self::VideoOffer(id, _) => write!(f, "VideoOffer: {:?}", id),
-            Self::VideoAnswer(id, _) => write!(f, "VideoAnswer: {:?}", id),
-            Self::ICECandidate(id, _) => write!(f, "ICECandidate: {:?}", id),
+            Self::PeerJoined(id, user, name, role) => {
+                write!(
+                    f,
+                    "PeerJoined: {:?}, {:?}, {:?}, {:?}",
+                    id, user, name, role
+                )
+            }
+            Self::SessionLeave(id) => write!(f, "SessionLeave: {:?}", id),
+            Self::SessionEnded(id) => write!(f, "SessionEnded: {:?}", id),
+            Self::PeerLeft(id, user) => write!(f, "PeerLeft: {:?}, {:?}", id, user),
+            Self::JoinRequest(user, name) => write!(f, "JoinRequest: {:?}, {:?}", user, name),
+            Self::JoinAccept(user) => write!(f, "JoinAccept: {:?}", user),
+            Self::JoinDeny(user) => write!(f, "JoinDeny: {:?}", user),
+            Self::Kick(user) => write!(f, "Kick: {:?}", user),
+            Self::Ban(user) => write!(f, "Ban: {:?}", user),
+            Self::RoleChanged(id, user, role) => {
+                write!(f, "RoleChanged: {:?}, {:?}, {:?}", id, user, role)
+            }
+            Self::SetPresenter(user) => write!(f, "SetPresenter: {:?}", user),
+            Self::SetCoHost(user, co_host) => write!(f, "SetCoHost: {:?}, {}", user, co_host),
+            Self::VideoOffer(id, user, _) => write!(f, "VideoOffer: {:?}, {:?}", id, user),
+            Self::VideoAnswer(id, user, _) => write!(f, "VideoAnswer: {:?}, {:?}", id, user),
+            Self::ICECandidate(id, user, _) => write!(f, "ICECandidate: {:?}, {:?}", id, user),
             Self::ICEError(id, _) => write!(f, "ICEError: {:?}", id),
+            Self::Chat(id, user, _) => write!(f, "Chat: {:?}, {:?}", id, user),
+            Self::Error(e) => write!(f, "Error: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(line: &str) -> IceCandidate {
+        IceCandidate {
+            candidate: line.to_string(),
+            sdp_mid: Some("0".to_string()),
+            sdp_m_line_index: None,
         }
     }
+
+    #[test]
+    fn accepts_session_description_of_expected_type() {
+        let offer = SessionDescription::new(SdpType::Offer, "v=0\r\no=- 1 2 IN IP4 0.0.0.0".into());
+        assert!(offer.validate(SdpType::Offer).is_ok());
+        assert!(offer.validate(SdpType::Answer).is_err());
+    }
+
+    #[test]
+    fn rejects_sdp_without_version_line() {
+        let answer = SessionDescription::new(SdpType::Answer, "o=- 1 2 IN IP4 0.0.0.0".into());
+        assert!(answer.validate(SdpType::Answer).is_err());
+    }
+
+    #[test]
+    fn accepts_rollback_without_sdp() {
+        let rollback = SessionDescription::new(SdpType::Rollback, String::new());
+        assert!(rollback.validate(SdpType::Rollback).is_ok());
+    }
+
+    #[test]
+    fn accepts_candidate_line() {
+        let line = "candidate:1 1 udp 2122260223 192.168.1.2 54321 typ host";
+        assert!(candidate(line).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_candidate() {
+        assert!(candidate("").validate().is_err());
+    }
+
+    #[test]
+    fn rejects_candidate_without_media_section() {
+        let mut candidate = candidate("candidate:1 1 udp 2122260223 192.168.1.2 54321 typ host");
+        candidate.sdp_mid = None;
+        assert!(candidate.validate().is_err());
+    }
 }